@@ -1,11 +1,72 @@
+#![cfg_attr(feature = "bench", feature(test))]
+
 use std::{
     borrow::Cow,
-    io::{self, BufRead, Write, stdout},
+    io::{self, BufRead, Read, Write, stdout},
+    net::{TcpStream, ToSocketAddrs},
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use phf::phf_map;
+use phf::{phf_map, phf_set};
+
+/// Emits a `tracing` debug event when built with the `tracing` feature and
+/// compiles to nothing otherwise.
+macro_rules! decision {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    }};
+}
+
+/// A diagnostic from the call path: a `tracing` warn event (inside the
+/// call's span) with the `tracing` feature, a line on stderr otherwise.
+macro_rules! warning {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        eprintln!($($arg)*);
+    }};
+}
 
 const SIX_DIGIT_PREFIX: &str = "73843";
 
+/// Numbering plans where 6-digit local numbers sit under different prefixes
+/// by their leading digits (e.g. landline vs mobile city blocks). First
+/// matching lead wins; anything else falls back to SIX_DIGIT_PREFIX. Add
+/// the prefixes to LOCAL_PREFIXES too.
+static SIX_DIGIT_PREFIX_BY_LEAD: &[(&str, &str)] = &[];
+
+/// The prefix a 6-digit local number expands under, by its first matching
+/// lead in `by_lead` (SIX_DIGIT_PREFIX_BY_LEAD).
+fn six_digit_prefix(by_lead: &[(&str, &'static str)], local: &str) -> &'static str {
+    by_lead.iter().find(|(lead, _)| local.starts_with(lead)).map_or(SIX_DIGIT_PREFIX, |&(_, p)| p)
+}
+
+/// A full number under one of the 6-digit expansion prefixes.
+fn in_local_block(by_lead: &[(&str, &str)], number: &str) -> bool {
+    number.starts_with(SIX_DIGIT_PREFIX) || by_lead.iter().any(|(_, p)| number.starts_with(p))
+}
+
+/// Opt-in: 7-digit city numbers (dialed without their area code) are
+/// expanded to this prefix + the 7 digits. Must make a full 11-digit number.
+const SEVEN_DIGIT_PREFIX: Option<&str> = None;
+
+/// Deployment test mode: every lookup is answered with this extension and
+/// TEST_MODE=TRUE, while the decision it would have made is logged.
+const TEST_REDIRECT: Option<&str> = None;
+
+/// Opt-in: a 3-digit dial that is no known extension is expanded to
+/// SIX_DIGIT_PREFIX + this segment + the 3 digits and placed as a local call
+/// instead of being rejected. Must make a full 11-digit number.
+const SHORT_DIAL_MIDDLE: Option<&str> = None;
+
+/// When set, an outbound dial of a full 11-digit number is always placed
+/// externally, even if that number is an inbound DID in NUMBER_TO_EXT.
+/// Short dials still resolve through the map, and so do local numbers under
+/// the 6-digit prefixes, so `602313` and `73843602313` always route the same.
+const FULL_NUMBERS_DIAL_EXTERNAL: bool = false;
+
 static NUMBER_TO_EXT: phf::Map<&'static str, &'static str> = phf_map! {
     "79235253998"=>"501","79235254061"=>"502","79235254150"=>"503","79235254132"=>"504",
     "79235254389"=>"505","79235254439"=>"506","79235254667"=>"507","79235254706"=>"508",
@@ -16,98 +77,1261 @@ static NUMBER_TO_EXT: phf::Map<&'static str, &'static str> = phf_map! {
     "111"=>"508","106"=>"509"
 };
 
-static EXT_TO_TRUNK: phf::Map<&'static str, &'static str> = phf_map! {
-    "501"=>"79235253998","502"=>"79235254061","503"=>"79235254150","504"=>"79235254132",
-    "505"=>"79235254389","506"=>"79235254439","507"=>"79235254667","508"=>"79235254706",
-    "509"=>"79235255049","510"=>"79235255136"
+/// Temporary number→ext routes, consulted before NUMBER_TO_EXT so they can be
+/// added and cleared without touching the main map.
+static NUMBER_OVERRIDES: phf::Map<&'static str, &'static str> = phf_map! {};
+
+/// Free-form key/value notes per routed number (department, cost center,
+/// ...), keyed like NUMBER_TO_EXT. Only keys listed in ROUTE_METADATA_VARS
+/// reach the dialplan; anything else stays in the config.
+static ROUTE_METADATA: phf::Map<&'static str, &'static [(&'static str, &'static str)]> = phf_map! {};
+
+/// Metadata key → variable set when a route carrying that key matches.
+const ROUTE_METADATA_VARS: &[(&str, &str)] = &[("dept", "ROUTE_DEPT"), ("cost_center", "ROUTE_COST_CENTER")];
+
+/// Sets the ROUTE_METADATA_VARS fields `metadata` (ROUTE_METADATA) holds for
+/// `number`.
+fn set_route_metadata(
+    w: &mut impl Write,
+    metadata: &phf::Map<&'static str, &'static [(&'static str, &'static str)]>,
+    number: &str,
+) -> io::Result<()> {
+    for (key, value) in metadata.get(number).copied().unwrap_or_default() {
+        if let Some((_, var)) = ROUTE_METADATA_VARS.iter().find(|(k, _)| k == key) {
+            set_var(w, var, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Number→destination tables, in the order they are consulted. Each
+/// direction lists its layers explicitly; the first layer with a match wins
+/// and later layers are never asked.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Layer {
+    /// NUMBER_OVERRIDES
+    Override,
+    /// NUMBER_TO_EXT
+    Exact,
+    /// DID_GROUPS
+    Group,
+    /// DID_SUFFIX_DIGITS
+    DidSuffix,
+}
+
+/// Outbound dials (after normalization) and caller checks.
+const OUTBOUND_LAYERS: &[Layer] = &[Layer::Override, Layer::Exact];
+/// Inbound DIDs: individual mappings before groups, explicit config before
+/// the suffix heuristic.
+const INBOUND_LAYERS: &[Layer] = &[Layer::Override, Layer::Exact, Layer::Group, Layer::DidSuffix];
+
+/// The tables behind each layer.
+struct Tables {
+    overrides: &'static phf::Map<&'static str, &'static str>,
+    exact: &'static phf::Map<&'static str, &'static str>,
+    groups: &'static [DidGroup],
+    suffix_digits: Option<usize>,
+}
+
+static TABLES: Tables = Tables {
+    overrides: &NUMBER_OVERRIDES,
+    exact: &NUMBER_TO_EXT,
+    groups: DID_GROUPS,
+    suffix_digits: DID_SUFFIX_DIGITS,
+};
+
+impl Tables {
+    fn did_group(&self, did: &str) -> Option<&'static DidGroup> {
+        self.groups.iter().find(|g| g.dids.contains(&did))
+    }
+}
+
+impl Layer {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Override => "override",
+            Self::Exact => "exact",
+            Self::Group => "group",
+            Self::DidSuffix => "did_suffix",
+        }
+    }
+
+    fn lookup(self, tables: &Tables, number: &str) -> Option<&'static str> {
+        match self {
+            Self::Override => tables.overrides.get(number).copied(),
+            Self::Exact => tables.exact.get(number).copied(),
+            Self::Group => tables.did_group(number).map(|g| g.target),
+            Self::DidSuffix => ext_from_did_suffix(number, tables.suffix_digits?),
+        }
+    }
+}
+
+fn resolve_layers(tables: &Tables, number: &str, layers: &[Layer]) -> Option<(Layer, &'static str)> {
+    layers.iter().find_map(|&l| l.lookup(tables, number).map(|target| (l, target)))
+}
+
+fn ext_for(number: &str) -> Option<&'static str> {
+    resolve_layers(&TABLES, number, OUTBOUND_LAYERS).map(|(_, ext)| ext)
+}
+
+struct Trunk {
+    name: &'static str,
+    tech: &'static str,
+    target_template: &'static str,
+    options: &'static str,
+    night_callerid: Option<&'static str>,
+    outbound_prefix: &'static str,
+    callerid_format: CallerIdFormat,
+}
+
+/// How a trunk's carrier wants the presented caller ID written.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CallerIdFormat {
+    /// As stored in the config.
+    Stored,
+    /// `7XXXXXXXXXX`
+    International,
+    /// `+7XXXXXXXXXX`
+    E164,
+    /// `XXXXXXXXXX`
+    National,
+}
+
+impl CallerIdFormat {
+    /// `None` if the number does not normalize to a full national number.
+    fn apply(self, number: &str) -> Option<Cow<'_, str>> {
+        if self == Self::Stored { return Some(Cow::Borrowed(number)); }
+        let n = sanitize_and_normalize(number).filter(|n| n.len() == 11)?;
+        Some(match self {
+            Self::Stored | Self::International => n,
+            Self::E164 => Cow::Owned(format!("+{}", n)),
+            Self::National => Cow::Owned(n[1..].to_owned()),
+        })
+    }
+}
+
+impl Trunk {
+    const fn bare(name: &'static str) -> Self {
+        Self {
+            name,
+            tech: "PJSIP",
+            target_template: "{target}@{trunk}",
+            options: "Ttr",
+            night_callerid: None,
+            outbound_prefix: "",
+            callerid_format: CallerIdFormat::Stored,
+        }
+    }
+
+    /// Caller ID presented on this trunk: the night caller ID out of hours,
+    /// otherwise the extension's own DID if it has one, otherwise the trunk.
+    /// Emergency calls pass `open` (see day_callerid) so they always present
+    /// the real number.
+    fn callerid<'a>(&'a self, open: bool, presented: Option<&'a str>) -> Cow<'a, str> {
+        let number = match self.night_callerid {
+            Some(night) if !open => night,
+            _ => presented.unwrap_or(self.name),
+        };
+        self.callerid_format.apply(number).unwrap_or(Cow::Borrowed(number))
+    }
+
+    fn dial_string(&self, target: &str) -> String {
+        let rendered = self.target_template.replace("{target}", target).replace("{trunk}", self.name);
+        let mut s = String::with_capacity(self.tech.len() + 1 + rendered.len());
+        s.push_str(self.tech);
+        s.push('/');
+        s.push_str(&rendered);
+        s
+    }
+}
+
+static EXT_TO_TRUNK: phf::Map<&'static str, Trunk> = phf_map! {
+    "501"=>Trunk::bare("79235253998"),"502"=>Trunk::bare("79235254061"),
+    "503"=>Trunk::bare("79235254150"),"504"=>Trunk::bare("79235254132"),
+    "505"=>Trunk::bare("79235254389"),"506"=>Trunk::bare("79235254439"),
+    "507"=>Trunk::bare("79235254667"),"508"=>Trunk::bare("79235254706"),
+    "509"=>Trunk::bare("79235255049"),"510"=>Trunk::bare("79235255136")
 };
 
+/// Alternate trunk per calling extension for `fallback` mode, which the
+/// dialplan runs after the primary trunk reported congestion; extensions
+/// not listed use FALLBACK_TRUNK.
+static FALLBACK_TRUNKS: phf::Map<&'static str, Trunk> = phf_map! {};
+
+static FALLBACK_TRUNK: Option<Trunk> = None;
+
+fn fallback_trunk<'a>(
+    trunks: &'a phf::Map<&'static str, Trunk>,
+    default: Option<&'a Trunk>,
+    caller_ext: &str,
+) -> Option<&'a Trunk> {
+    trunks.get(caller_ext).or(default)
+}
+
+/// Digit prefixes of premium-rate numbers, matched on the sanitized dial
+/// before normalization so short forms like `0900...` are accepted. They are
+/// dialed as-is with IS_PREMIUM=TRUE over PREMIUM_TRUNK (or the caller's own
+/// trunk when that is `None`). Emergency numbers are matched first and never
+/// count as premium.
+const PREMIUM_PREFIXES: &[&str] = &[];
+
+static PREMIUM_TRUNK: Option<Trunk> = None;
+
+/// International access prefixes (e.g. `810`, `00`). A dial starting with
+/// one is stripped to its E.164 digits and placed with IS_INTERNATIONAL=TRUE
+/// over INTERNATIONAL_TRUNK (or the caller's trunk when that is `None`). A
+/// home number in this form (`8107...`) is normalized as national instead.
+/// `8` + 10 digits never matches `810`: home area codes do not start with 0/1.
+const INTERNATIONAL_ACCESS_PREFIXES: &[&str] = &[];
+const MIN_INTERNATIONAL_LEN: usize = 7;
+
+/// Place `+`-prefixed numbers outside the home country code as international
+/// calls. When off they fail with foreign_number.
+const ROUTE_E164_INTERNATIONAL: bool = false;
+
+static INTERNATIONAL_TRUNK: Option<Trunk> = None;
+
+/// What an 11-digit dial that is not a home number (does not start with 7
+/// or 8) means.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum ForeignElevenPolicy {
+    /// Fail with `unknown_country` rather than guess.
+    Reject,
+    /// A national number of this country: the code is prepended and the
+    /// call placed as international.
+    Country(&'static str),
+}
+
+/// Country codes an 11-digit dial may already start with (e.g. `1` for
+/// NANP). Such dials go out as international as they are.
+const FOREIGN_ELEVEN_CODES: &[&str] = &[];
+
+const FOREIGN_ELEVEN_POLICY: ForeignElevenPolicy = ForeignElevenPolicy::Reject;
+
+/// International number for an ambiguous 11-digit dial, `Ok(None)` when the
+/// dial is not one. `codes` and `policy` are FOREIGN_ELEVEN_CODES and
+/// FOREIGN_ELEVEN_POLICY.
+fn foreign_eleven(dialed: &str, codes: &[&str], policy: ForeignElevenPolicy) -> Result<Option<String>, FailureReason> {
+    let digits = match just_sanitize(dialed) {
+        Some(d) if d.len() == 11 && !is_e164(dialed) && !d.starts_with(['7', '8']) => d,
+        _ => return Ok(None),
+    };
+    if codes.iter().any(|c| digits.starts_with(c)) {
+        return Ok(Some(digits.into_owned()));
+    }
+    match policy {
+        ForeignElevenPolicy::Reject => Err(FailureReason::UnknownCountry),
+        ForeignElevenPolicy::Country(code) => Ok(Some(format!("{}{}", code, digits))),
+    }
+}
+
+/// Per-extension caller ID for extensions that share a trunk but must present
+/// their own number.
+static PRESENTED_DIDS: phf::Map<&'static str, &'static str> = phf_map! {};
+
+/// Extensions whose outbound calls withhold their caller ID (CLIR):
+/// OUTBOUND_CLIR=TRUE and presentation set to prohibited. The network-level
+/// number (DIAL_CALLERID) is still set.
+static CLIR_EXTENSIONS: phf::Set<&'static str> = phf_set! {};
+
+fn withholds_callerid(clir: &phf::Set<&'static str>, caller_ext: Option<&str>) -> bool {
+    caller_ext.is_some_and(|e| clir.contains(e))
+}
+
+/// Emergency numbers, as dialed. They are checked before anything else on
+/// an outbound dial and go straight out over the caller's trunk, whatever
+/// the caller policies, block lists and permissions say; CLIR never applies
+/// to them. None of them may also be an internal route.
+const EMERGENCY_NUMBERS: &[&str] = &["112", "101", "102", "103"];
+
+fn is_emergency(number: &str) -> bool {
+    EMERGENCY_NUMBERS.contains(&number)
+}
+
+/// HTTP directory holding per-user presentation numbers, asked before
+/// PRESENTED_DIDS: `GET path` (`{ext}` filled in) on `addr`, answered with
+/// the number as a plain-text or JSON-string body. 404 means no entry. Any
+/// other answer, or no answer within `timeout_ms`, falls back to the static
+/// config. Give `addr` as ip:port so no DNS lookup eats into the budget.
+struct Directory { addr: &'static str, host: &'static str, path: &'static str, timeout_ms: u64 }
+
+static CLI_DIRECTORY: Option<Directory> = None;
+
+/// Caps what is read from the directory; a presentation number is tiny.
+const MAX_DIRECTORY_RESPONSE: u64 = 4096;
+
+fn directory_cli(call_id: &str, ext: &str) -> Option<String> {
+    let d = CLI_DIRECTORY.as_ref()?;
+    fetch_cli(d, ext).unwrap_or_else(|e| {
+        warning!("fastagi_router[{}]: CLI directory lookup for {} failed: {}", call_id, ext, e);
+        None
+    })
+}
+
+fn fetch_cli(d: &Directory, ext: &str) -> io::Result<Option<String>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let timeout = Duration::from_millis(d.timeout_ms);
+    let addr = d.addr.to_socket_addrs()?.next().ok_or_else(|| invalid("no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json, text/plain\r\nConnection: close\r\n\r\n",
+        d.path.replace("{ext}", ext),
+        d.host,
+    )?;
+    let mut response = Vec::new();
+    stream.take(MAX_DIRECTORY_RESPONSE).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| invalid("truncated response"))?;
+    match head.split(' ').nth(1) {
+        Some("200") => {}
+        Some("404") => return Ok(None),
+        _ => return Err(invalid(head.lines().next().unwrap_or(""))),
+    }
+    let cli = body.trim().trim_matches('"');
+    let digits = cli.strip_prefix('+').unwrap_or(cli);
+    if digits.is_empty() || digits.len() > MAX_DIGITS || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid("response is not a number"));
+    }
+    Ok(Some(cli.to_owned()))
+}
+
+/// Local business hours as minutes since midnight, Monday-first weekday mask.
+struct Schedule { utc_offset_min: i64, open: i64, close: i64, weekdays: u8 }
+
+impl Schedule {
+    fn is_open(&self, unix_secs: i64) -> bool {
+        let local = unix_secs.div_euclid(60) + self.utc_offset_min;
+        let (days, minute) = (local.div_euclid(1440), local.rem_euclid(1440));
+        // 1970-01-01 was a Thursday.
+        let weekday = (days + 3).rem_euclid(7);
+        self.weekdays & (1 << weekday) != 0 && minute >= self.open && minute < self.close
+    }
+}
+
+const BUSINESS_HOURS: Schedule = Schedule { utc_offset_min: 7 * 60, open: 9 * 60, close: 18 * 60, weekdays: 0b0011111 };
+
+/// Source of "now" (Unix seconds, UTC) for every time-dependent decision.
+/// Schedules apply their own UTC offset, so the clock stays zone-free.
+trait Clock { fn now(&self) -> i64; }
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+    }
+}
+
+/// A frozen instant, for evaluating routes as of a given time.
+struct FixedClock(i64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> i64 { self.0 }
+}
+
+/// External numbers dialed over a trunk but reported to the dialplan as
+/// internal destinations, e.g. a branch office reached over SIP. Keyed by the
+/// normalized number.
+static REPORT_AS_INTERNAL: phf::Set<&'static str> = phf_set! {};
+
+/// DTMF sent to the far end once an external number answers, keyed by the
+/// normalized number: digits, `*`, `#`, and `w`/`W`/`,` for pauses.
+static DIAL_SEQUENCES: phf::Map<&'static str, &'static str> = phf_map! {};
+
+const DIAL_SEQUENCE_CHARS: &str = "0123456789*#wW,";
+
+fn is_dial_sequence(seq: &str) -> bool {
+    !seq.is_empty() && seq.chars().all(|c| DIAL_SEQUENCE_CHARS.contains(c))
+}
+
+/// Separators between a number and a PBX sub-extension in a dial such as
+/// `79235253998x123`, matched case-insensitively; tried in order. The
+/// sub-extension is sent as DTMF after answer. Empty disables the split.
+const SUB_EXTENSION_SEPARATORS: &[&str] = &["ext", "x", ","];
+
+/// Sent before a sub-extension so the far end's attendant is listening.
+const SUB_EXTENSION_PAUSE: &str = "ww";
+
+/// Splits `number<sep>digits` into the number and the sub-extension. Input
+/// without a separator followed only by digits comes back unchanged.
+fn split_extension(s: &str) -> (&str, Option<&str>) {
+    let lower = s.to_ascii_lowercase();
+    for sep in SUB_EXTENSION_SEPARATORS {
+        let Some(i) = lower.rfind(sep) else { continue };
+        let ext = s[i + sep.len()..].trim_start_matches(|c: char| c == '.' || c.is_whitespace()).trim_end();
+        let number = s[..i].trim_end();
+        if !ext.is_empty() && ext.bytes().all(|b| b.is_ascii_digit()) && number.bytes().any(|b| b.is_ascii_digit()) {
+            return (number, Some(ext));
+        }
+    }
+    (s, None)
+}
+
+/// Also emit DIALED_ORIGINAL/DIALED_NORMALIZED on outbound lookups, for
+/// tracking down misroutes (`verbose` in handle_outbound).
+const VERBOSE_VARS: bool = false;
+
+/// Emit ROUTER_TRACE, a one-line summary of the decision path, e.g.
+/// `mode=outbound;norm=79235253998;map=hit;ext=501`. On in debug builds.
+const EMIT_TRACE: bool = cfg!(debug_assertions);
+const MAX_TRACE_LEN: usize = 256;
+
+/// Flush after every SET VARIABLE instead of once when the lookup is done.
+/// Costs a write per variable; only worth it if Asterisk must see each
+/// variable as soon as it is decided.
+const FLUSH_EACH_VAR: bool = false;
+
+/// How boolean variables (LOOKUP_SUCCESS, IS_*, ...) are written. The
+/// dialplan's comparisons must use the same style.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BoolStyle { TrueFalse, OneZero, YesNo }
+
+const BOOL_STYLE: BoolStyle = BoolStyle::TrueFalse;
+
+impl BoolStyle {
+    fn render(self, b: bool) -> &'static str {
+        match (self, b) {
+            (Self::TrueFalse, true) => "TRUE",
+            (Self::TrueFalse, false) => "FALSE",
+            (Self::OneZero, true) => "1",
+            (Self::OneZero, false) => "0",
+            (Self::YesNo, true) => "yes",
+            (Self::YesNo, false) => "no",
+        }
+    }
+}
+
+fn flag(b: bool) -> &'static str {
+    BOOL_STYLE.render(b)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Quoting { Quoted, Unquoted }
+
+const DEFAULT_QUOTING: Quoting = Quoting::Quoted;
+
+static VAR_QUOTING: phf::Map<&'static str, Quoting> = phf_map! {};
+
+/// Escapes a value for a quoted AGI argument. Control characters (a newline
+/// would end the command) are dropped rather than escaped.
+fn escape_value(v: &str) -> Cow<'_, str> {
+    if !v.contains(|c: char| c == '"' || c == '\\' || c.is_control()) { return Cow::Borrowed(v); }
+    let mut s = String::with_capacity(v.len() + 2);
+    for c in v.chars().filter(|c| !c.is_control()) {
+        if c == '"' || c == '\\' { s.push('\\'); }
+        s.push(c);
+    }
+    Cow::Owned(s)
+}
+
 fn set_var<W: Write>(w: &mut W, name: &str, value: &str) -> io::Result<()> {
-    writeln!(w, "SET VARIABLE {} \"{}\"", name, value)?;
-    w.flush()
+    set_var_as(w, name, value, VAR_QUOTING.get(name).copied().unwrap_or(DEFAULT_QUOTING))
+}
+
+fn set_var_as<W: Write>(w: &mut W, name: &str, value: &str, quoting: Quoting) -> io::Result<()> {
+    // An empty or spaced value would split the command, so it is quoted anyway.
+    let bare = quoting == Quoting::Unquoted
+        && !value.is_empty()
+        && !value.contains(|c: char| c.is_whitespace() || c.is_control() || c == '"' || c == '\\');
+    if bare {
+        writeln!(w, "SET VARIABLE {} {}", name, value)?;
+    } else {
+        writeln!(w, "SET VARIABLE {} \"{}\"", name, escape_value(value))?;
+    }
+    if FLUSH_EACH_VAR { w.flush()?; }
+    Ok(())
+}
+
+/// Read Asterisk's reply to each command before sending the next. Off by
+/// default: one-shot lookups only set variables and never need the replies.
+const READ_REPLIES: bool = false;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply { Ok, Failed(u16), Hangup }
+
+/// Feature codes the router answers itself instead of routing: the dialed
+/// string (as sent, e.g. `"*43"=>("Echo", "")`) runs the application via
+/// `EXEC` and the call is hung up. Requires READ_REPLIES, so each command
+/// completes before the next is sent.
+static FEATURE_CODES: phf::Map<&'static str, (&'static str, &'static str)> = phf_map! {};
+
+fn run_feature_code(w: &mut impl Write, app: &str, args: &str) -> io::Result<()> {
+    writeln!(w, "EXEC {} \"{}\"", app, escape_value(args))?;
+    writeln!(w, "HANGUP")
+}
+
+/// Reads one command reply, skipping blank and unrecognised lines and the
+/// body of multi-line `520-...` usage replies. An asynchronous `HANGUP`
+/// line, or EOF, means the channel is gone.
+fn read_reply(r: &mut impl BufRead) -> io::Result<Reply> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 { return Ok(Reply::Hangup); }
+        let l = line.trim();
+        if l.eq_ignore_ascii_case("HANGUP") { return Ok(Reply::Hangup); }
+        let Some(code) = l.get(..3).and_then(|c| c.parse::<u16>().ok()) else { continue };
+        if l.as_bytes().get(3) == Some(&b'-') {
+            let end = format!("{} ", code);
+            loop {
+                line.clear();
+                if r.read_line(&mut line)? == 0 { return Ok(Reply::Hangup); }
+                if line.starts_with(&end) { break; }
+            }
+        }
+        return Ok(if code == 200 { Reply::Ok } else { Reply::Failed(code) });
+    }
+}
+
+/// Writer that waits for Asterisk's reply after every complete command line.
+/// A hangup surfaces as a `BrokenPipe` error so the lookup stops like any
+/// other peer close. `command` collects the line being written, which may
+/// arrive in pieces, so a failure can name it.
+struct AgiChannel<R, W> { call_id: String, reader: R, writer: W, command: Vec<u8> }
+
+impl<R, W> AgiChannel<R, W> {
+    fn new(call_id: String, reader: R, writer: W) -> Self {
+        Self { call_id, reader, writer, command: Vec::new() }
+    }
+}
+
+fn failed_command_warning(call_id: &str, command: &[u8], code: u16) -> String {
+    format!("fastagi_router[{}]: AGI command {:?} failed with {}", call_id, String::from_utf8_lossy(command), code)
+}
+
+impl<R: BufRead, W: Write> Write for AgiChannel<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(i) = buf.iter().position(|&b| b == b'\n') else {
+            let n = self.writer.write(buf)?;
+            self.command.extend_from_slice(&buf[..n]);
+            return Ok(n);
+        };
+        self.writer.write_all(&buf[..=i])?;
+        self.writer.flush()?;
+        self.command.extend_from_slice(&buf[..i]);
+        let command = std::mem::take(&mut self.command);
+        match read_reply(&mut self.reader)? {
+            Reply::Ok => {}
+            Reply::Failed(code) => warning!("{}", failed_command_warning(&self.call_id, &command, code)),
+            Reply::Hangup => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "channel hung up")),
+        }
+        Ok(i + 1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+}
+
+/// Debugging aid: append an exact copy of every byte sent to Asterisk to
+/// this file. Failing to open or write it never affects the AGI output.
+const CAPTURE_FILE: Option<&str> = None;
+
+/// Duplicates output to a secondary sink. The primary's result is what the
+/// caller sees; a secondary error is logged once and the sink is dropped.
+struct Tee<W, S> { call_id: String, primary: W, secondary: Option<S> }
+
+impl<W: Write, S: Write> Tee<W, S> {
+    fn secondary(&mut self, op: impl FnOnce(&mut S) -> io::Result<()>) {
+        if let Some(Err(e)) = self.secondary.as_mut().map(op) {
+            warning!("fastagi_router[{}]: capture disabled: {}", self.call_id, e);
+            self.secondary = None;
+        }
+    }
+}
+
+impl<W: Write, S: Write> Write for Tee<W, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.primary.write(buf)?;
+        self.secondary(|s| s.write_all(&buf[..n]));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.primary.flush()?;
+        self.secondary(Write::flush);
+        Ok(())
+    }
+}
+
+/// Data-quality audit: outbound dials that were not already in canonical
+/// form (separators stripped, 8 rewritten to 7, local numbers expanded) are
+/// appended here as `call_id<TAB>dialed<TAB>normalized`, to find the phones
+/// and sources that send badly formatted numbers. Canonical dials are not
+/// logged. Separate from the decision trace.
+const NORMALIZATION_AUDIT_FILE: Option<&str> = None;
+
+/// Mask audit entries: only the last N digits of each number are kept.
+const AUDIT_KEEP_DIGITS: Option<usize> = None;
+
+fn mask_digits(s: &str, keep: Option<usize>) -> Cow<'_, str> {
+    let Some(keep) = keep else { return Cow::Borrowed(s) };
+    let mut hide = s.bytes().filter(u8::is_ascii_digit).count().saturating_sub(keep);
+    Cow::Owned(s.chars().map(|c| if c.is_ascii_digit() && hide > 0 { hide -= 1; '*' } else { c }).collect())
+}
+
+/// The audit entry for one normalization, or `None` for a canonical dial.
+/// `keep` is AUDIT_KEEP_DIGITS.
+fn audit_entry(call_id: &str, dialed: &str, normalized: &str, keep: Option<usize>) -> Option<String> {
+    (dialed != normalized).then(|| {
+        format!("{}\t{}\t{}\n", call_id, escape_value(&mask_digits(dialed, keep)), mask_digits(normalized, keep))
+    })
+}
+
+fn audit_normalization(call_id: &str, dialed: &str, normalized: &str) {
+    let Some(path) = NORMALIZATION_AUDIT_FILE else { return };
+    let Some(line) = audit_entry(call_id, dialed, normalized, AUDIT_KEEP_DIGITS) else { return };
+    let written = std::fs::OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        warning!("fastagi_router[{}]: cannot write normalization audit {}: {}", call_id, path, e);
+    }
+}
+
+fn open_capture(call_id: &str) -> Option<std::fs::File> {
+    let path = CAPTURE_FILE?;
+    std::fs::OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| warning!("fastagi_router[{}]: cannot open capture file {}: {}", call_id, path, e))
+        .ok()
+}
+
+/// LOOKUP_REASON codes, for failures and for deliberate blocks alike.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum FailureReason {
+    WrongLength,
+    ShortInternalRejected,
+    EmptyDial,
+    UnknownInboundDid,
+    BadMode,
+    MalformedCaller,
+    NotPermitted,
+    SelfCall,
+    RegionBlocked,
+    NoFallback,
+    NotDialable,
+    UnknownExtension,
+    MalformedNationalNumber,
+    BadStep,
+    NoTrunkForExtension,
+    UnknownCountry,
+    ForeignNumber,
+}
+
+/// Per-deployment replacements for LOOKUP_REASON strings, keyed by the
+/// default code (e.g. `"empty_dial"=>"1"`).
+static REASON_STRINGS: phf::Map<&'static str, &'static str> = phf_map! {};
+
+impl FailureReason {
+    const ALL: &'static [Self] = &[
+        Self::WrongLength, Self::ShortInternalRejected, Self::EmptyDial, Self::UnknownInboundDid,
+        Self::BadMode, Self::MalformedCaller, Self::NotPermitted, Self::SelfCall,
+        Self::RegionBlocked, Self::NoFallback, Self::NotDialable, Self::UnknownExtension,
+        Self::MalformedNationalNumber, Self::BadStep,
+        Self::NoTrunkForExtension, Self::UnknownCountry, Self::ForeignNumber,
+    ];
+
+    fn code(self) -> &'static str {
+        match self {
+            Self::WrongLength => "normalize_failed_wrong_length",
+            Self::ShortInternalRejected => "short_internal_rejected",
+            Self::EmptyDial => "empty_dial",
+            Self::UnknownInboundDid => "unknown_inbound_did",
+            Self::BadMode => "bad_mode",
+            Self::MalformedCaller => "malformed_caller",
+            Self::NotPermitted => "not_permitted",
+            Self::SelfCall => "self_call",
+            Self::RegionBlocked => "region_blocked",
+            Self::NoFallback => "no_fallback",
+            Self::NotDialable => "not_dialable",
+            Self::UnknownExtension => "unknown_extension",
+            Self::MalformedNationalNumber => "malformed_national_number",
+            Self::BadStep => "bad_step",
+            Self::NoTrunkForExtension => "no_trunk_for_extension",
+            Self::UnknownCountry => "unknown_country",
+            Self::ForeignNumber => "foreign_number",
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        self.as_str_in(&REASON_STRINGS)
+    }
+
+    /// The LOOKUP_REASON string, with `overrides` (REASON_STRINGS) replacing
+    /// the default code.
+    fn as_str_in(self, overrides: &phf::Map<&'static str, &'static str>) -> &'static str {
+        overrides.get(self.code()).copied().unwrap_or(self.code())
+    }
 }
 
+#[derive(Debug)]
 enum LookupStatus<'a> {
     Internal(&'a str),
-    External(String), 
-    Failure(&'a str),
+    /// `report_internal` marks trunk-dialed routes the dialplan should still
+    /// treat as internal (IS_INTERNAL_DEST=TRUE).
+    External { target: String, report_internal: bool },
+    Failure(FailureReason),
+    /// A number that was understood but deliberately not dialed. Reported as
+    /// LOOKUP_SUCCESS with BLOCKED set, so the dialplan can play a specific
+    /// announcement instead of the generic failure path.
+    Blocked(FailureReason),
 }
 
 impl<'a> LookupStatus<'a> {
-    fn into_parts(self) -> (&'static str, &'static str, Cow<'a, str>, &'a str) {
+    /// `(kind, target-or-reason)` as printed by `--batch` and `--classify`.
+    fn summary(self) -> (&'static str, Cow<'a, str>) {
+        match self {
+            Self::Internal(ext) => ("internal", Cow::Borrowed(ext)),
+            Self::External { target, .. } => ("external", Cow::Owned(target)),
+            Self::Failure(r) => ("failure", Cow::Borrowed(r.as_str())),
+            Self::Blocked(r) => ("blocked", Cow::Borrowed(r.as_str())),
+        }
+    }
+
+    /// (success, internal, blocked, target, reason)
+    fn into_parts(self) -> (bool, bool, bool, Cow<'a, str>, &'static str) {
         match self {
-            Self::Internal(t) => ("TRUE", "TRUE", Cow::Borrowed(t), ""),
-            Self::External(t) => ("TRUE", "FALSE", Cow::Owned(t), ""),
-            Self::Failure(r) => ("FALSE", "FALSE", Cow::Borrowed(""), r),
+            Self::Internal(t) => (true, true, false, Cow::Borrowed(t), ""),
+            Self::External { target, report_internal } => (true, report_internal, false, Cow::Owned(target), ""),
+            Self::Failure(r) => (false, false, false, Cow::Borrowed(""), r.as_str()),
+            Self::Blocked(r) => (true, false, true, Cow::Borrowed(""), r.as_str()),
         }
     }
 }
 
+/// How the lookup result reaches the dialplan. `Variables` only sets the
+/// variables for the dialplan to inspect; `Goto`/`Gosub` also send the
+/// channel to a destination built from the STEER_* templates, via `EXEC`.
+/// Those need READ_REPLIES.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Steering { Variables, Goto, Gosub }
+
+const STEERING: Steering = Steering::Variables;
+
+/// `context,exten,priority` templates; `{target}` is replaced by the
+/// extension, the external number, or the failure reason.
+const STEER_INTERNAL: &str = "router-internal,{target},1";
+const STEER_EXTERNAL: &str = "router-external,{target},1";
+const STEER_FAILURE: &str = "router-failed,{target},1";
+
 fn set_lookup<W: Write>(status: LookupStatus, w: &mut W) -> io::Result<()> {
-    let (succ, internal, target_cow, reason) = status.into_parts();
+    let (succ, internal, blocked, target_cow, reason) = status.into_parts();
     let target = target_cow.as_ref();
-    set_var(w, "LOOKUP_SUCCESS", succ)?;
-    set_var(w, "IS_INTERNAL_DEST", internal)?;
+    set_var(w, "LOOKUP_SUCCESS", flag(succ))?;
+    set_var(w, "IS_INTERNAL_DEST", flag(internal))?;
     set_var(w, "DIAL_TARGET", target)?; 
-    if succ == "FALSE" { set_var(w, "LOOKUP_REASON", reason)?; }
-    Ok(())
+    set_var(w, "BLOCKED", flag(blocked))?;
+    if !succ || blocked { set_var(w, "LOOKUP_REASON", reason)?; }
+    match steer_command(STEERING, succ && !blocked, internal, target, reason) {
+        Some(command) => writeln!(w, "{}", command),
+        None => Ok(()),
+    }
+}
+
+/// The `EXEC` sending the channel on under `steering`: `routed` lookups go
+/// to their internal or external destination, the rest to the failure one
+/// with their `reason`. `None` for `Variables`.
+fn steer_command(steering: Steering, routed: bool, internal: bool, target: &str, reason: &str) -> Option<String> {
+    let app = match steering {
+        Steering::Variables => return None,
+        Steering::Goto => "Goto",
+        Steering::Gosub => "Gosub",
+    };
+    let destination = match (routed, internal) {
+        (false, _) => STEER_FAILURE.replace("{target}", reason),
+        (_, true) => STEER_INTERNAL.replace("{target}", target),
+        _ => STEER_EXTERNAL.replace("{target}", target),
+    };
+    Some(format!("EXEC {} \"{}\"", app, escape_value(&destination)))
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum Mode { Inbound, Outbound }
+enum Mode { Inbound, Outbound, Fallback, Device, FollowMe }
+
+/// Mode used when none of the mode SOURCES has a value.
+const DEFAULT_MODE: Mode = Mode::Outbound;
 
 impl Mode {
-    fn from_str(s: &str) -> Self {
-        match s { "inbound" => Self::Inbound, _ => Self::Outbound }
+    /// `None` for a mode string that is present but not recognised.
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "" => Some(DEFAULT_MODE),
+            "inbound" => Some(Self::Inbound),
+            "outbound" => Some(Self::Outbound),
+            "fallback" => Some(Self::Fallback),
+            "device" => Some(Self::Device),
+            "followme" => Some(Self::FollowMe),
+            _ => None,
+        }
+    }
+}
+
+/// Upper bound on environment lines before the terminating blank line, so a
+/// peer that keeps streaming keys cannot hold the process forever.
+const MAX_ENV_LINES: usize = 128;
+
+/// Upper bound on one environment line, newline included; a longer line is
+/// rejected rather than buffered.
+const MAX_ENV_LINE_BYTES: u64 = 4096;
+
+/// Whitespace policy for `agi_*` values. `Trim` strips it; `Preserve` only
+/// drops the single space Asterisk puts after the colon. Either way a value
+/// wrapped in double quotes loses the quotes, and its inner content then
+/// follows the same policy.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ValueWhitespace { Trim, Preserve }
+
+const VALUE_WHITESPACE: ValueWhitespace = ValueWhitespace::Trim;
+
+fn env_value(raw: &str, policy: ValueWhitespace) -> &str {
+    let v = match policy {
+        ValueWhitespace::Trim => raw.trim(),
+        ValueWhitespace::Preserve => raw.strip_prefix(' ').unwrap_or(raw),
+    };
+    match v.trim().strip_prefix('"').and_then(|q| q.strip_suffix('"')) {
+        Some(inner) if policy == ValueWhitespace::Trim => inner.trim(),
+        Some(inner) => inner,
+        None => v,
     }
 }
 
-struct AgiVars { dialed: String, caller: String, mode: Mode }
+/// AGI environment keys the dialed number, caller and mode are read from,
+/// in order: the first one present with a non-blank value wins. Lets one
+/// build serve dialplans that pass the number differently, e.g.
+/// `&["agi_arg_1", "agi_extension"]` when some contexts call the router
+/// without arguments. Channel variables are not in the environment and
+/// have to be passed as an argument.
+struct Sources { dialed: &'static [&'static str], caller: &'static [&'static str], mode: &'static [&'static str] }
+
+const SOURCES: Sources = Sources { dialed: &["agi_arg_1"], caller: &["agi_arg_2"], mode: &["agi_arg_3"] };
+
+struct AgiVars {
+    dialed: String,
+    caller: String,
+    mode: Option<Mode>,
+    request: String,
+    call_id: String,
+    channel: String,
+    /// `agi_arg_4`: the step index in `followme` mode.
+    step: String,
+}
+
+/// `agi_uniqueid` reduced to characters that are safe inside SET VARIABLE.
+fn sanitize_call_id(s: &str) -> String {
+    s.chars().filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')).take(64).collect()
+}
+
+/// A UUIDv4-shaped id for calls that arrive without `agi_uniqueid`.
+fn generate_call_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    let mut x = nanos ^ (u64::from(std::process::id()) << 32);
+    let mut next = || {
+        x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = x;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let (hi, lo) = (next(), next());
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        hi >> 32, (hi >> 16) & 0xffff, hi & 0xfff, (lo >> 48) & 0x3fff | 0x8000, lo & 0xffff_ffff_ffff,
+    )
+}
 
 impl AgiVars {
-    fn from_stdin() -> io::Result<Self> {
-        let mut dialed = String::new();
-        let mut caller = String::new();
-        let mut mode = Mode::Outbound;
-        let stdin = io::stdin();
-        for line in stdin.lock().lines() {
-            let line = line?;
-            let l = line.trim();
-            if l.is_empty() { break; }
-            if let Some((k, v)) = l.split_once(':') {
+    /// Builds a request directly, for driving the routing without an AGI
+    /// handshake. The call ID is generated; override it with `with_call_id`.
+    fn new(dialed: impl Into<String>, caller: impl Into<String>, mode: Mode) -> Self {
+        Self {
+            dialed: dialed.into(),
+            caller: caller.into(),
+            mode: Some(mode),
+            request: String::new(),
+            call_id: generate_call_id(),
+            channel: String::new(),
+            step: String::new(),
+        }
+    }
+
+    fn with_call_id(mut self, call_id: &str) -> Self {
+        self.call_id = sanitize_call_id(call_id);
+        self
+    }
+
+    fn with_channel(mut self, channel: &str) -> Self {
+        self.channel = channel.to_owned();
+        self
+    }
+
+    /// Reads the AGI environment up to its terminating blank line, taking
+    /// the dialed number, caller and mode from the first of their `sources`
+    /// with a value.
+    fn from_reader(mut r: impl BufRead, sources: &Sources) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut sourced: Vec<(String, String)> = Vec::new();
+        let mut request = String::new();
+        let mut call_id = String::new();
+        let mut channel = String::new();
+        let mut step = String::new();
+        let mut raw = Vec::new();
+        for n in 0.. {
+            raw.clear();
+            if (&mut r).take(MAX_ENV_LINE_BYTES).read_until(b'\n', &mut raw)? == 0 { break; }
+            if raw.last() != Some(&b'\n') && raw.len() as u64 == MAX_ENV_LINE_BYTES {
+                return Err(invalid(format!("AGI environment line longer than {} bytes", MAX_ENV_LINE_BYTES)));
+            }
+            let line = std::str::from_utf8(&raw).map_err(|e| invalid(e.to_string()))?;
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if line.trim().is_empty() { break; }
+            if n == MAX_ENV_LINES {
+                return Err(invalid(format!("AGI environment not terminated within {} lines", MAX_ENV_LINES)));
+            }
+            if let Some((k, v)) = line.split_once(':') {
                 let k = k.trim();
-                let v = v.trim();
+                let v = env_value(v, VALUE_WHITESPACE);
+                let wanted = [sources.dialed, sources.caller, sources.mode].iter().any(|s| s.contains(&k));
+                if wanted && !v.trim().is_empty() {
+                    sourced.push((k.to_owned(), v.to_owned()));
+                }
                 match k {
-                    "agi_arg_1" => dialed = v.to_owned(),
-                    "agi_arg_2" => caller = v.to_owned(),
-                    "agi_arg_3" => mode = Mode::from_str(v),
+                    "agi_request" => request = v.to_owned(),
+                    "agi_uniqueid" => call_id = sanitize_call_id(v),
+                    "agi_channel" => channel = v.to_owned(),
+                    "agi_arg_4" => step = v.to_owned(),
                     _ => {}
                 }
             }
         }
-        Ok(Self { dialed, caller, mode })
+        if call_id.is_empty() { call_id = generate_call_id(); }
+        let first = |sources: &[&str]| {
+            sources.iter().find_map(|s| sourced.iter().find(|(k, _)| k == s)).map_or("", |(_, v)| v.as_str())
+        };
+        let (dialed, caller) = (first(sources.dialed).to_owned(), first(sources.caller).to_owned());
+        let mode = Mode::from_str(first(sources.mode).trim());
+        Ok(Self { dialed, caller, mode, request, call_id, channel, step })
+    }
+
+    /// A load-balancer probe requests `agi://host/health`; it gets a reply
+    /// without running a lookup.
+    fn is_health_probe(&self) -> bool {
+        self.request.trim_end_matches('/').ends_with("/health")
     }
 }
 
-fn just_sanitize(s: &str) -> Option<Cow<'_, str>> {
-    let digits = if s.chars().all(|c| c.is_ascii_digit()) { 
-        Cow::Borrowed(s)
-    } else {
-        Cow::Owned(s.chars().filter(|c| c.is_ascii_digit()).collect())
-    };
-    (!digits.is_empty()).then_some(digits)
+/// DIDs sharing one route. The target goes out as DIAL_TARGET and may be an
+/// extension, hunt group, queue or IVR the dialplan knows; the group name is
+/// emitted as DID_GROUP.
+struct DidGroup { name: &'static str, dids: &'static [&'static str], target: &'static str }
+
+static DID_GROUPS: &[DidGroup] = &[];
+
+/// Channel-name prefixes (from `agi_channel`) and the technology class they
+/// are reported as in INBOUND_TECH. First match wins; anything else is
+/// UNKNOWN_TECH.
+static CHANNEL_TECHS: &[(&str, &str)] = &[
+    ("DAHDI/", "pstn"),
+    ("PJSIP/", "sip"),
+    ("SIP/", "sip"),
+    ("IAX2/", "iax"),
+    ("Local/", "internal"),
+];
+
+const UNKNOWN_TECH: &str = "unknown";
+
+fn channel_tech(channel: &str) -> &'static str {
+    CHANNEL_TECHS.iter().find(|(p, _)| channel.starts_with(p)).map_or(UNKNOWN_TECH, |&(_, t)| t)
 }
 
-fn sanitize_and_normalize(s: &str) -> Option<Cow<'_, str>> {
-    let digits = just_sanitize(s)?;
-    match digits.len() {
-        3 => Some(digits),
-        6 => {
-            let mut n = String::with_capacity(SIX_DIGIT_PREFIX.len() + 6);
-            n.push_str(SIX_DIGIT_PREFIX);
+/// Inbound DIDs that route differently depending on the technology class
+/// the call arrived over (e.g. a test SIP trunk vs the PSTN). Consulted
+/// before INBOUND_LAYERS.
+struct TechRoute { tech: &'static str, did: &'static str, target: &'static str }
+
+static TECH_ROUTES: &[TechRoute] = &[];
+
+fn tech_route(routes: &[TechRoute], tech: &str, did: &str) -> Option<&'static str> {
+    routes.iter().find(|r| r.tech == tech && r.did == did).map(|r| r.target)
+}
+
+/// For DID blocks whose last digits are the extension (`...0501` → 501): an
+/// inbound DID missing from the maps is tried as its last N digits. Explicit
+/// map entries always win.
+const DID_SUFFIX_DIGITS: Option<usize> = None;
+
+/// Per-DID recording policy for inbound calls; unlisted DIDs get
+/// RECORD_BY_DEFAULT.
+static DID_RECORDING: phf::Map<&'static str, bool> = phf_map! {};
+
+const RECORD_BY_DEFAULT: bool = false;
+
+fn should_record(policy: &phf::Map<&'static str, bool>, did: &str) -> bool {
+    policy.get(did).copied().unwrap_or(RECORD_BY_DEFAULT)
+}
+
+/// Prompt language per inbound DID, emitted as CALL_LANGUAGE so the dialplan
+/// can set CHANNEL(language); unlisted DIDs get DEFAULT_LANGUAGE.
+static DID_LANGUAGE: phf::Map<&'static str, &'static str> = phf_map! {};
+
+const DEFAULT_LANGUAGE: &str = "ru";
+
+/// Languages there are prompts for; anything else is a config error.
+const KNOWN_LANGUAGES: &[&str] = &["ru", "en"];
+
+fn call_language(languages: &phf::Map<&'static str, &'static str>, did: &str) -> &'static str {
+    languages.get(did).copied().unwrap_or(DEFAULT_LANGUAGE)
+}
+
+/// Inbound: when the caller ID is one of our own numbers, also emit the
+/// extension it maps to as CALLER_EXTENSION (informational, e.g. for
+/// presentation or billing; `caller_extension` in handle_inbound).
+const EMIT_CALLER_EXTENSION: bool = false;
+
+/// How far an extension may dial, in increasing order of reach.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Scope { Internal, Local, National, International }
+
+const LOCAL_PREFIXES: &[&str] = &[SIX_DIGIT_PREFIX];
+const HOME_COUNTRY_CODE: &str = "7";
+const NATIONAL_LENGTH: usize = 11;
+
+/// Report country-code-prefixed numbers of the wrong length as
+/// malformed_national_number rather than the generic length failure.
+const DIAGNOSE_NATIONAL_LENGTH: bool = true;
+
+impl Scope {
+    /// Reach of a normalized external number.
+    fn of(number: &str) -> Self {
+        if LOCAL_PREFIXES.iter().any(|p| number.starts_with(p)) { Self::Local }
+        else if is_home_national(number) { Self::National }
+        else { Self::International }
+    }
+}
+
+/// Outbound permission per calling extension; unlisted ones get
+/// DEFAULT_PERMISSION. Emergency numbers are always allowed.
+static EXT_PERMISSIONS: phf::Map<&'static str, Scope> = phf_map! {};
+
+const DEFAULT_PERMISSION: Scope = Scope::International;
+
+fn permission(permissions: &phf::Map<&'static str, Scope>, caller_ext: Option<&str>) -> Scope {
+    caller_ext.and_then(|e| permissions.get(e)).copied().unwrap_or(DEFAULT_PERMISSION)
+}
+
+/// Whether `caller_ext` may dial `target` under `permissions`
+/// (EXT_PERMISSIONS).
+fn permitted(permissions: &phf::Map<&'static str, Scope>, caller_ext: Option<&str>, target: &str) -> bool {
+    is_emergency(target) || Scope::of(target) <= permission(permissions, caller_ext)
+}
+
+/// Fraud control: external numbers (with country code, no access prefix)
+/// under these prefixes fail with region_blocked, unless BLOCK_EXCEPTIONS
+/// has a match at least as long as the longest blocked one.
+const BLOCKED_PREFIXES: &[&str] = &[];
+const BLOCK_EXCEPTIONS: &[&str] = &[];
+
+/// Normalized numbers that are recognised but never dialed (informational
+/// lines and the like); they come back as blocked with not_dialable.
+static NO_DIAL: phf::Set<&'static str> = phf_set! {};
+
+fn longest_prefix(number: &str, prefixes: &[&str]) -> Option<usize> {
+    prefixes.iter().filter(|p| number.starts_with(*p)).map(|p| p.len()).max()
+}
+
+fn region_blocked(number: &str, blocked: &[&str], exceptions: &[&str]) -> bool {
+    longest_prefix(number, blocked)
+        .is_some_and(|blocked| longest_prefix(number, exceptions).is_none_or(|allowed| allowed < blocked))
+}
+
+/// MAX_CALL_SECONDS hint for external calls, for an absolute timeout in the
+/// dialplan. The calling extension's entry wins over the destination scope's;
+/// with neither (and no default) no variable is emitted. Emergency calls
+/// never carry a limit.
+static EXT_MAX_CALL_SECONDS: phf::Map<&'static str, u32> = phf_map! {};
+const SCOPE_MAX_CALL_SECONDS: &[(Scope, u32)] = &[];
+const DEFAULT_MAX_CALL_SECONDS: Option<u32> = None;
+
+fn max_call_seconds(
+    per_ext: &phf::Map<&'static str, u32>,
+    per_scope: &[(Scope, u32)],
+    default: Option<u32>,
+    caller_ext: Option<&str>,
+    target: &str,
+) -> Option<u32> {
+    if is_emergency(target) { return None; }
+    let scope = Scope::of(target);
+    caller_ext.and_then(|e| per_ext.get(e)).copied()
+        .or_else(|| per_scope.iter().find(|(s, _)| *s == scope).map(|&(_, secs)| secs))
+        .or(default)
+}
+
+/// Caller ID values Asterisk reports for withheld numbers, compared
+/// case-insensitively. An empty caller is always treated as withheld.
+const ANONYMOUS_CALLERS: &[&str] = &["anonymous", "unknown", "restricted", "private", "withheld"];
+
+/// Extension that takes inbound calls from withheld numbers, e.g. a screening
+/// prompt. `None` delivers them to the DID's extension as usual.
+const ANONYMOUS_SCREENING_EXT: Option<&str> = None;
+
+fn is_anonymous(caller: &str) -> bool {
+    let caller = caller.trim();
+    caller.is_empty() || ANONYMOUS_CALLERS.iter().any(|a| a.eq_ignore_ascii_case(caller))
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CallerPolicy { Ignore, Warn, Reject }
+
+/// What to do with an outbound caller that is neither a 3-digit extension nor
+/// a full number (e.g. a truncated 2-digit caller ID).
+const MALFORMED_CALLER_POLICY: CallerPolicy = CallerPolicy::Ignore;
+
+/// `policy`'s verdict on an outbound caller: the failure reason under
+/// Reject. 3-digit extensions, full numbers and empty callers always pass.
+fn malformed_caller(policy: CallerPolicy, call_id: &str, caller: &str) -> Option<FailureReason> {
+    let digits = just_sanitize(caller)?;
+    if digits.len() == 3 || digits.len() >= 11 { return None; }
+    match policy {
+        CallerPolicy::Ignore => None,
+        CallerPolicy::Warn => {
+            warning!("{}", malformed_caller_warning(call_id, caller));
+            None
+        }
+        CallerPolicy::Reject => Some(FailureReason::MalformedCaller),
+    }
+}
+
+fn malformed_caller_warning(call_id: &str, caller: &str) -> String {
+    format!("fastagi_router[{}]: malformed caller {:?}", call_id, caller)
+}
+
+/// What to do when an extension with no trunk in EXT_TO_TRUNK dials out:
+/// place the call without a trunk (Ignore/Warn) or fail it with
+/// no_trunk_for_extension.
+const MISSING_TRUNK_POLICY: CallerPolicy = CallerPolicy::Reject;
+
+/// `policy`'s verdict on extension `ext` dialing out with no trunk.
+fn missing_trunk(policy: CallerPolicy, call_id: &str, ext: &str) -> Option<FailureReason> {
+    match policy {
+        CallerPolicy::Ignore => None,
+        CallerPolicy::Warn => {
+            warning!("fastagi_router[{}]: extension {} dials out without a trunk", call_id, ext);
+            None
+        }
+        CallerPolicy::Reject => Some(FailureReason::NoTrunkForExtension),
+    }
+}
+
+/// Longest plausible number (E.164). More digits than this after stripping
+/// separators means two numbers ran together or the input is junk.
+const MAX_DIGITS: usize = 15;
+
+/// Longest raw number string looked at, separators and URI parts included.
+/// Bounds the work on hostile input before any digit is counted.
+const MAX_NUMBER_INPUT: usize = 256;
+
+/// User part of a `scheme:user@host;params` URI (`sip:501@pbx12` → `501`),
+/// so digits in the host never leak into the number. Other input is
+/// returned unchanged.
+fn uri_user(s: &str) -> &str {
+    match s.split_once(':') {
+        Some((scheme, rest)) if !scheme.is_empty() && scheme.bytes().all(|b| b.is_ascii_alphabetic()) => {
+            rest.split(['@', ';']).next().unwrap_or("")
+        }
+        _ => s,
+    }
+}
+
+/// Non-digit characters that survive sanitizing of a dialed value for
+/// feature-code matching (e.g. `"*#"` for MMI sequences). Separators are
+/// still stripped. Empty: feature codes match the dial as sent.
+const DIAL_KEEP_CHARS: &str = "";
+
+fn just_sanitize(s: &str) -> Option<Cow<'_, str>> {
+    sanitize_keeping(s, "")
+}
+
+/// Digits plus any of `keep`, everything else stripped.
+fn sanitize_keeping<'a>(s: &'a str, keep: &str) -> Option<Cow<'a, str>> {
+    if s.len() > MAX_NUMBER_INPUT { return None; }
+    let s = uri_user(s);
+    let kept = |b: &u8| b.is_ascii_digit() || keep.as_bytes().contains(b);
+    // Stops scanning as soon as the cap is exceeded.
+    let count = s.bytes().filter(kept).take(MAX_DIGITS + 1).count();
+    if count == 0 || count > MAX_DIGITS { return None; }
+    if count == s.len() { return Some(Cow::Borrowed(s)); }
+    Some(Cow::Owned(s.bytes().filter(kept).map(char::from).collect()))
+}
+
+/// Key a dial is looked up under in FEATURE_CODES; `keep` is DIAL_KEEP_CHARS.
+fn feature_code<'a>(dialed: &'a str, keep: &str) -> Cow<'a, str> {
+    if keep.is_empty() { return Cow::Borrowed(dialed.trim()); }
+    sanitize_keeping(dialed, keep).unwrap_or_default()
+}
+
+/// Remainder of a number dialed with one of the international access
+/// `prefixes` (INTERNATIONAL_ACCESS_PREFIXES).
+fn strip_access_prefix<'a>(digits: &'a str, prefixes: &[&str]) -> Option<&'a str> {
+    prefixes.iter()
+        .find_map(|p| digits.strip_prefix(p))
+        .filter(|rest| rest.len() >= MIN_INTERNATIONAL_LEN)
+}
+
+fn is_home_national(number: &str) -> bool {
+    number.len() == NATIONAL_LENGTH && number.starts_with(HOME_COUNTRY_CODE)
+}
+
+/// A leading `+` means the digits are already E.164 with a country code,
+/// so national rewrites (8→7, local expansion) must not touch them.
+fn is_e164(s: &str) -> bool {
+    uri_user(s).trim_start().starts_with('+')
+}
+
+/// A dial placed as an international call: the digits after one of the
+/// access `prefixes`, or with `route_e164` (ROUTE_E164_INTERNATIONAL) a
+/// `+`-prefixed number, as long as it is not a home number.
+fn international_number(dialed: &str, prefixes: &[&str], route_e164: bool) -> Option<String> {
+    let d = just_sanitize(dialed)?;
+    let rest = if is_e164(dialed) {
+        Some(&*d).filter(|r| route_e164 && r.len() >= MIN_INTERNATIONAL_LEN)
+    } else {
+        strip_access_prefix(&d, prefixes)
+    };
+    rest.filter(|r| !is_home_national(r)).map(str::to_owned)
+}
+
+/// A 7-digit city number under `prefix` (SEVEN_DIGIT_PREFIX); `None` if
+/// 7-digit dials are not expanded.
+fn expand_seven_digit(digits: &str, prefix: Option<&str>) -> Option<String> {
+    prefix.map(|p| format!("{}{}", p, digits))
+}
+
+fn sanitize_and_normalize(s: &str) -> Option<Cow<'_, str>> {
+    let digits = just_sanitize(s)?;
+    if is_e164(s) {
+        return is_home_national(&digits).then_some(digits);
+    }
+    // A home number dialed in international form (`8107...`, `007...`).
+    if let Some(rest) = strip_access_prefix(&digits, INTERNATIONAL_ACCESS_PREFIXES).filter(|r| is_home_national(r)) {
+        return Some(Cow::Owned(rest.to_owned()));
+    }
+    match digits.len() {
+        3 => Some(digits),
+        6 => {
+            let prefix = six_digit_prefix(SIX_DIGIT_PREFIX_BY_LEAD, &digits);
+            let mut n = String::with_capacity(prefix.len() + 6);
+            n.push_str(prefix);
             n.push_str(&digits);
             Some(Cow::Owned(n))
         }
+        7 => expand_seven_digit(&digits, SEVEN_DIGIT_PREFIX).map(Cow::Owned),
         11 => {
             let first = digits.as_bytes()[0];
             if first == b'7' { Some(digits) }
@@ -116,61 +1340,1054 @@ fn sanitize_and_normalize(s: &str) -> Option<Cow<'_, str>> {
                 n.push('7');
                 n.push_str(&digits[1..]);
                 Some(Cow::Owned(n))
-            } else { None }
+            } else { None } // foreign or ambiguous, see foreign_eleven
         }
         _ => None,
     }
 }
 
-fn handle_outbound(vars: AgiVars, w: &mut impl Write) -> io::Result<LookupStatus<'static>> {
+/// Whether a dial's NUMBER_TO_EXT hit is ignored because `full_external`
+/// (FULL_NUMBERS_DIAL_EXTERNAL) places full 11-digit dials externally. Local
+/// numbers under the 6-digit prefixes are exempt so they route like their
+/// 6-digit form.
+fn full_number_shadowed(dialed: &str, normalized: &str, full_external: bool) -> bool {
+    full_external
+        && !in_local_block(SIX_DIGIT_PREFIX_BY_LEAD, normalized)
+        && just_sanitize(dialed).is_some_and(|d| d.len() == 11)
+}
+
+/// A 3-digit dial that is no known extension, as a local number under
+/// `middle` (SHORT_DIAL_MIDDLE); `None` if short dials are not expanded.
+fn expand_short_dial(short: &str, middle: Option<&str>) -> Option<String> {
+    middle.map(|m| format!("{}{}{}", SIX_DIGIT_PREFIX, m, short))
+}
+
+/// A phone dialing its own number: caller and dialed normalize to the same
+/// digits. Usually a misconfigured device, and dialing it would only loop.
+/// Outbound also catches an extension dialing a number that routes back to
+/// itself.
+fn is_self_call(caller: &str, dialed: &str) -> bool {
+    sanitize_and_normalize(caller)
+        .or_else(|| just_sanitize(caller))
+        .is_some_and(|c| c == dialed)
+}
+
+/// DIAL_TRUNK, plus the trunk's parts for dialplans that build their own
+/// Dial string: DIAL_TRUNK_NUMBER always, DIAL_TRUNK_TECH when the trunk
+/// has a technology.
+fn set_trunk(w: &mut impl Write, t: &Trunk) -> io::Result<()> {
+    set_var(w, "DIAL_TRUNK", t.name)?;
+    if !t.tech.is_empty() { set_var(w, "DIAL_TRUNK_TECH", t.tech)?; }
+    set_var(w, "DIAL_TRUNK_NUMBER", t.name)
+}
+
+/// Reason for a dial that failed normalization. With
+/// DIAGNOSE_NATIONAL_LENGTH, a number that starts with the home country code
+/// but is not NATIONAL_LENGTH digits is reported as a malformed national
+/// number (usually a digit dropped or doubled when it was typed in). A
+/// `+`-prefixed number in another country is foreign_number, never a
+/// length problem.
+fn length_failure(dialed: &str) -> FailureReason {
+    let Some(d) = just_sanitize(dialed) else { return FailureReason::WrongLength };
+    let home = d.starts_with(HOME_COUNTRY_CODE);
+    if is_e164(dialed) && !home && d.len() >= MIN_INTERNATIONAL_LEN {
+        return FailureReason::ForeignNumber;
+    }
+    let national_typo = DIAGNOSE_NATIONAL_LENGTH && home && d.len() != NATIONAL_LENGTH;
+    if national_typo { FailureReason::MalformedNationalNumber } else { FailureReason::WrongLength }
+}
+
+/// Whether a call at `now` presents the day caller ID: in business hours,
+/// and for emergency calls at any hour.
+fn day_callerid(now: i64, emergency: bool) -> bool {
+    emergency || BUSINESS_HOURS.is_open(now)
+}
+
+/// Outbound precedence, first match wins:
+/// 1. emergency numbers (dialed as-is over the caller's trunk, if any)
+/// 2. premium prefixes (dialed as-is over PREMIUM_TRUNK)
+/// 3. international access prefixes / foreign E.164 / foreign 11-digit
+///    dials (FOREIGN_ELEVEN_POLICY)
+/// 4. normalization, then unknown 3-digit expansion (SHORT_DIAL_MIDDLE)
+/// 5. OUTBOUND_LAYERS, unless FULL_NUMBERS_DIAL_EXTERNAL shadows the hit
+/// 6. external dial over the caller's trunk
+///
+/// In fallback mode the caller's trunk comes from FALLBACK_TRUNKS and the
+/// dedicated premium/international trunks are skipped, so the retry never
+/// goes out over the route that just failed. `caller_policy` is
+/// MALFORMED_CALLER_POLICY; like a missing fallback trunk, it never stops an
+/// emergency call. A `dry_run` makes the same decision without side effects:
+/// no directory fetch, no audit entry.
+fn handle_outbound(
+    vars: AgiVars,
+    now: i64,
+    verbose: bool,
+    caller_policy: CallerPolicy,
+    fallback: bool,
+    dry_run: bool,
+    w: &mut impl Write,
+) -> io::Result<LookupStatus<'static>> {
+    if verbose { set_var(w, "DIALED_ORIGINAL", &vars.dialed)?; }
+    let (dialed, sub_ext) = split_extension(&vars.dialed);
+    if !uri_user(dialed).bytes().any(|b| b.is_ascii_digit()) {
+        return Ok(LookupStatus::Failure(FailureReason::EmptyDial));
+    }
+    let emergency = just_sanitize(dialed).filter(|d| is_emergency(d));
+    let mut trunk = None;
+    let mut caller_ext = None;
+    let mut presented = None;
     if let Some(caller) = just_sanitize(&vars.caller) {
+        let malformed = malformed_caller(caller_policy, &vars.call_id, &vars.caller);
+        if let (Some(reason), None) = (malformed, &emergency) {
+            return Ok(LookupStatus::Failure(reason));
+        }
         if caller.len() == 3 {
-            if let Some(&trunk) = EXT_TO_TRUNK.get(&caller) {
-                set_var(w, "DIAL_TRUNK", trunk)?;
+            let t = if fallback {
+                fallback_trunk(&FALLBACK_TRUNKS, FALLBACK_TRUNK.as_ref(), &caller)
+            } else {
+                EXT_TO_TRUNK.get(&caller)
+            };
+            if let Some(t) = t {
+                decision!(trunk = t.name, "trunk selected by caller extension");
+                presented = presented_did(&vars.call_id, &caller, dry_run);
+                trunk = Some(t);
             }
+            caller_ext = Some(caller);
         }
     }
-    let normalized = match sanitize_and_normalize(&vars.dialed).ok_or(
-        LookupStatus::Failure("normalize_failed_wrong_length"),
-    ) {
-        Ok(n) => n,
-        Err(status) => return Ok(status),
-    };
-    Ok(match NUMBER_TO_EXT.get(&normalized) {
-        Some(&ext) => LookupStatus::Internal(ext),
-        None => if normalized.len() == 3 {
-            LookupStatus::Failure("short_internal_rejected")
-        } else {
-            LookupStatus::External(match normalized {
-                Cow::Borrowed(s) => s.to_owned(),
-                Cow::Owned(s) => s,
-            })
+    let caller = Caller { ext: caller_ext.as_deref(), did: presented.as_deref() };
+    if let Some(number) = emergency {
+        decision!(number = %number, "emergency number");
+        return dial_external(&vars.call_id, number.into_owned(), trunk, caller, None, now, w);
+    }
+    if fallback && trunk.is_none() {
+        return Ok(LookupStatus::Failure(FailureReason::NoFallback));
+    }
+    if withholds_callerid(&CLIR_EXTENSIONS, caller.ext) {
+        set_var(w, "OUTBOUND_CLIR", flag(true))?;
+        set_var(w, "CALLERID(pres)", "prohib")?;
+    }
+    let dedicated = |t: &'static Option<Trunk>| if fallback { None } else { t.as_ref() };
+    if let Some(number) = premium_number(dialed, PREMIUM_PREFIXES) {
+        decision!(number = %number, "premium number");
+        set_var(w, "IS_PREMIUM", flag(true))?;
+        return dial_external(&vars.call_id, number.into_owned(), dedicated(&PREMIUM_TRUNK).or(trunk), caller, sub_ext, now, w);
+    }
+    let international = international_number(dialed, INTERNATIONAL_ACCESS_PREFIXES, ROUTE_E164_INTERNATIONAL);
+    let international = match international {
+        Some(number) => Some(number),
+        None => match foreign_eleven(dialed, FOREIGN_ELEVEN_CODES, FOREIGN_ELEVEN_POLICY) {
+            Ok(number) => number,
+            Err(reason) => return Ok(LookupStatus::Failure(reason)),
         },
+    };
+    if let Some(number) = international {
+        decision!(number = %number, "international number");
+        set_var(w, "IS_INTERNATIONAL", flag(true))?;
+        return dial_external(&vars.call_id, number, dedicated(&INTERNATIONAL_TRUNK).or(trunk), caller, sub_ext, now, w);
+    }
+    let normalized = match sanitize_and_normalize(dialed) {
+        Some(n) => n,
+        None => return Ok(LookupStatus::Failure(length_failure(dialed))),
+    };
+    let normalized = if normalized.len() == 3 && ext_for(&normalized).is_none() {
+        match expand_short_dial(&normalized, SHORT_DIAL_MIDDLE) {
+            Some(expanded) => Cow::Owned(expanded),
+            None => return Ok(LookupStatus::Failure(FailureReason::ShortInternalRejected)),
+        }
+    } else { normalized };
+    decision!(normalized = %normalized, "dialed number normalized");
+    if !dry_run { audit_normalization(&vars.call_id, &vars.dialed, &normalized); }
+    if is_self_call(&vars.caller, &normalized) {
+        return Ok(LookupStatus::Failure(FailureReason::SelfCall));
+    }
+    if verbose { set_var(w, "DIALED_NORMALIZED", &normalized)?; }
+    let shadowed = full_number_shadowed(dialed, &normalized, FULL_NUMBERS_DIAL_EXTERNAL);
+    match ext_for(&normalized).filter(|_| !shadowed) {
+        Some(ext) if caller.ext == Some(ext) => Ok(LookupStatus::Failure(FailureReason::SelfCall)),
+        Some(ext) => {
+            set_route_metadata(w, &ROUTE_METADATA, &normalized)?;
+            Ok(LookupStatus::Internal(ext))
+        }
+        None => dial_external(&vars.call_id, normalized.into_owned(), trunk, caller, sub_ext, now, w),
+    }
+}
+
+/// The sanitized dial if it starts with one of `prefixes` (PREMIUM_PREFIXES).
+fn premium_number<'a>(dialed: &'a str, prefixes: &[&str]) -> Option<Cow<'a, str>> {
+    just_sanitize(dialed).filter(|d| prefixes.iter().any(|p| d.starts_with(p)))
+}
+
+/// The extension placing an external dial, if any, and the DID it presents
+/// (see presented_did).
+#[derive(Clone, Copy)]
+struct Caller<'a> { ext: Option<&'a str>, did: Option<&'a str> }
+
+/// The DID `ext` presents: its DIRECTORY_URL entry, else PRESENTED_DIDS. A
+/// `dry_run` skips the directory.
+fn presented_did(call_id: &str, ext: &str, dry_run: bool) -> Option<String> {
+    let fetched = if dry_run { None } else { directory_cli(call_id, ext) };
+    fetched.or_else(|| PRESENTED_DIDS.get(ext).map(|&d| d.to_owned()))
+}
+
+/// An external dial of `target` if `caller`'s permission reaches it, with
+/// its call length hint, REPORT_AS_INTERNAL flag and DIAL_SEQUENCES entry;
+/// a `sub_ext` split off the dial is sent instead of the sequence. Emergency
+/// numbers skip the trunk, block and permission checks. This is the one
+/// place the trunk variables and DIAL_CALLERID are set, for `trunk` as
+/// finally chosen.
+fn dial_external(
+    call_id: &str,
+    target: String,
+    trunk: Option<&Trunk>,
+    caller: Caller,
+    sub_ext: Option<&str>,
+    now: i64,
+    w: &mut impl Write,
+) -> io::Result<LookupStatus<'static>> {
+    let emergency = is_emergency(&target);
+    let caller_ext = caller.ext;
+    if let (None, Some(ext), false) = (trunk, caller_ext, emergency) {
+        if let Some(reason) = missing_trunk(MISSING_TRUNK_POLICY, call_id, ext) {
+            return Ok(LookupStatus::Failure(reason));
+        }
+    }
+    if !emergency && NO_DIAL.contains(target.as_str()) {
+        decision!(target = %target, "number on the no-dial list");
+        return Ok(LookupStatus::Blocked(FailureReason::NotDialable));
+    }
+    if !emergency && region_blocked(&target, BLOCKED_PREFIXES, BLOCK_EXCEPTIONS) {
+        decision!(target = %target, "destination in a blocked region");
+        return Ok(LookupStatus::Blocked(FailureReason::RegionBlocked));
+    }
+    if !permitted(&EXT_PERMISSIONS, caller_ext, &target) {
+        decision!(
+            scope = ?Scope::of(&target), permission = ?permission(&EXT_PERMISSIONS, caller_ext),
+            "destination beyond caller's permission",
+        );
+        return Ok(LookupStatus::Failure(FailureReason::NotPermitted));
+    }
+    let max_seconds = max_call_seconds(
+        &EXT_MAX_CALL_SECONDS, SCOPE_MAX_CALL_SECONDS, DEFAULT_MAX_CALL_SECONDS, caller_ext, &target,
+    );
+    if let Some(secs) = max_seconds {
+        set_var(w, "MAX_CALL_SECONDS", &secs.to_string())?;
+    }
+    let report_internal = REPORT_AS_INTERNAL.contains(target.as_str());
+    let dtmf = match sub_ext {
+        Some(ext) => Some(Cow::Owned(format!("{}{}", SUB_EXTENSION_PAUSE, ext))),
+        None => DIAL_SEQUENCES.get(target.as_str()).map(|&seq| Cow::Borrowed(seq)),
+    };
+    if let Some(t) = trunk {
+        decision!(trunk = t.name, "trunk chosen for the dial");
+        set_trunk(w, t)?;
+        set_var(w, "DIAL_CALLERID", &t.callerid(day_callerid(now, emergency), caller.did))?;
+    }
+    place_external(target, trunk, dtmf.as_deref(), report_internal, w)
+}
+
+/// Places `target` over `trunk`, with the trunk's outbound prefix in front of
+/// the number and `dtmf` sent once the far end answers.
+fn place_external(
+    mut target: String,
+    trunk: Option<&Trunk>,
+    dtmf: Option<&str>,
+    report_internal: bool,
+    w: &mut impl Write,
+) -> io::Result<LookupStatus<'static>> {
+    if let Some(seq) = dtmf {
+        set_var(w, "DIAL_POST_DTMF", seq)?;
+    }
+    if let Some(t) = trunk {
+        target.insert_str(0, t.outbound_prefix);
+        set_var(w, "DIAL_STRING", &t.dial_string(&target))?;
+        match dtmf {
+            Some(seq) => set_var(w, "DIAL_OPTIONS", &format!("{}D({})", t.options, seq))?,
+            None => set_var(w, "DIAL_OPTIONS", t.options)?,
+        }
+    }
+    Ok(LookupStatus::External { target, report_internal })
+}
+
+/// Extension named by the last `n` digits of a DID, if that extension exists.
+fn ext_from_did_suffix(did: &str, n: usize) -> Option<&'static str> {
+    let suffix = did.get(did.len().checked_sub(n)?..)?;
+    EXT_TO_TRUNK.get_key(suffix).copied()
+}
+
+fn handle_inbound(vars: AgiVars, caller_extension: bool, w: &mut impl Write) -> io::Result<LookupStatus<'static>> {
+    let caller_ext = sanitize_and_normalize(&vars.caller).and_then(|c| ext_for(&c));
+    set_var(w, "IS_INTERNAL_CALLER", flag(caller_ext.is_some()))?;
+    if let (true, Some(ext)) = (caller_extension, caller_ext) {
+        set_var(w, "CALLER_EXTENSION", ext)?;
+    }
+    let anonymous = is_anonymous(&vars.caller);
+    set_var(w, "IS_ANONYMOUS", flag(anonymous))?;
+    let dialed = match just_sanitize(&vars.dialed) {
+        None => return Ok(LookupStatus::Failure(FailureReason::EmptyDial)),
+        Some(d) => d,
+    };
+    if is_self_call(&vars.caller, sanitize_and_normalize(&vars.dialed).as_deref().unwrap_or(&dialed)) {
+        return Ok(LookupStatus::Failure(FailureReason::SelfCall));
+    }
+    let record = should_record(&DID_RECORDING, &dialed);
+    set_var(w, "SHOULD_RECORD", flag(record))?;
+    set_var(w, "CALL_LANGUAGE", call_language(&DID_LANGUAGE, &dialed))?;
+    let tech = channel_tech(&vars.channel);
+    set_var(w, "INBOUND_TECH", tech)?;
+    let resolved = match tech_route(TECH_ROUTES, tech, &dialed) {
+        Some(target) => {
+            decision!(tech, target, "inbound DID routed by channel technology");
+            Some(target)
+        }
+        None => {
+            let resolved = resolve_layers(&TABLES, &dialed, INBOUND_LAYERS);
+            decision!(layer = ?resolved.map(|(l, _)| l), "inbound DID resolved");
+            if let (Some((Layer::Group, _)), Some(g)) = (resolved, TABLES.did_group(&dialed)) {
+                set_var(w, "DID_GROUP", g.name)?;
+            }
+            resolved.map(|(_, ext)| ext)
+        }
+    };
+    if resolved.is_some() {
+        set_route_metadata(w, &ROUTE_METADATA, &dialed)?;
+    }
+    Ok(match resolved {
+        Some(ext) => LookupStatus::Internal(if anonymous { ANONYMOUS_SCREENING_EXT.unwrap_or(ext) } else { ext }),
+        None => LookupStatus::Failure(FailureReason::UnknownInboundDid),
     })
 }
 
-fn run_lookup(vars: AgiVars, w: &mut impl Write) -> io::Result<()> {
-    let status = match vars.mode {
-        Mode::Outbound => handle_outbound(vars, w)?,
-        Mode::Inbound => {
-            let dialed = match just_sanitize(&vars.dialed) {
-                None => {
-                    set_lookup(LookupStatus::Failure("empty_dial"), w)?;
-                    return Ok(());
+/// Device for an extension's `hint` line, for BLF/presence, named the same
+/// way the dialplan dials it: DEVICE_TECH/<ext> unless DEVICE_OVERRIDES says
+/// otherwise.
+const DEVICE_TECH: &str = "PJSIP";
+
+static DEVICE_OVERRIDES: phf::Map<&'static str, &'static str> = phf_map! {};
+
+/// `device` mode: resolves the extension in agi_arg_1 to DEVICE_STATE_TARGET.
+/// Read-only; nothing is routed.
+fn handle_device(vars: &AgiVars, w: &mut impl Write) -> io::Result<LookupStatus<'static>> {
+    let ext = vars.dialed.trim();
+    let known = EXT_TO_TRUNK.get_key(ext)
+        .or_else(|| NUMBER_TO_EXT.values().chain(NUMBER_OVERRIDES.values()).find(|&&e| e == ext))
+        .copied();
+    let Some(ext) = known else { return Ok(LookupStatus::Failure(FailureReason::UnknownExtension)) };
+    match DEVICE_OVERRIDES.get(ext) {
+        Some(device) => set_var(w, "DEVICE_STATE_TARGET", device)?,
+        None => set_var(w, "DEVICE_STATE_TARGET", &format!("{}/{}", DEVICE_TECH, ext))?,
+    }
+    Ok(LookupStatus::Internal(ext))
+}
+
+/// One follow-me step: an extension, or an external number dialed over the
+/// owner's trunk, rung for `timeout_secs`.
+struct FollowStep { target: &'static str, timeout_secs: u32 }
+
+/// Follow-me sequences per extension (desk, then mobile, ...). Once the
+/// steps run out the call goes to the extension's voicemail.
+static FOLLOW_ME: phf::Map<&'static str, &'static [FollowStep]> = phf_map! {};
+
+/// `followme` mode: agi_arg_1 is the extension and agi_arg_4 the step (0
+/// when absent). Emits FOLLOW_ME_TIMEOUT and FOLLOW_ME_NEXT for the
+/// dialplan's loop, or FOLLOW_ME_DONE=TRUE with the extension as
+/// DIAL_TARGET when it is time for voicemail. `sequences` is FOLLOW_ME. An
+/// external step goes out over the extension's trunk with its caller ID,
+/// like the extension dialing it itself; a `dry_run` skips the directory.
+fn handle_follow_me(
+    vars: &AgiVars,
+    sequences: &phf::Map<&'static str, &'static [FollowStep]>,
+    now: i64,
+    dry_run: bool,
+    w: &mut impl Write,
+) -> io::Result<LookupStatus<'static>> {
+    let Some((&ext, steps)) = sequences.get_entry(vars.dialed.trim()) else {
+        return Ok(LookupStatus::Failure(FailureReason::UnknownExtension));
+    };
+    let step = match vars.step.trim() {
+        "" => 0,
+        s => match s.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => return Ok(LookupStatus::Failure(FailureReason::BadStep)),
+        },
+    };
+    let Some(s) = steps.get(step) else {
+        set_var(w, "FOLLOW_ME_DONE", flag(true))?;
+        return Ok(LookupStatus::Internal(ext));
+    };
+    set_var(w, "FOLLOW_ME_DONE", flag(false))?;
+    set_var(w, "FOLLOW_ME_TIMEOUT", &s.timeout_secs.to_string())?;
+    set_var(w, "FOLLOW_ME_NEXT", &(step + 1).to_string())?;
+    if EXT_TO_TRUNK.contains_key(s.target) {
+        return Ok(LookupStatus::Internal(s.target));
+    }
+    match sanitize_and_normalize(s.target) {
+        Some(n) => {
+            let trunk = EXT_TO_TRUNK.get(ext);
+            let did = trunk.and_then(|_| presented_did(&vars.call_id, ext, dry_run));
+            let caller = Caller { ext: Some(ext), did: did.as_deref() };
+            dial_external(&vars.call_id, n.into_owned(), trunk, caller, None, now, w)
+        }
+        None => Ok(LookupStatus::Failure(length_failure(s.target))),
+    }
+}
+
+enum Outcome { Done, PeerClosed }
+
+/// Routes calls. The tables are compiled in; the router owns what varies per
+/// instance, which for now is the Clock used for business hours. Built once
+/// in `main` and shared by every lookup, including a whole `--batch` run.
+///
+/// An AGI process serves one call, so nothing may depend on state left by an
+/// earlier one: a lookup is a function of the tables, the request and the
+/// clock. A feature that needs history must either reduce to that (e.g. a
+/// hash of the caller instead of round-robin) or be a no-op unless it has an
+/// external store.
+struct Router { clock: Box<dyn Clock> }
+
+impl Router {
+    fn new() -> Self { Self::with_clock(SystemClock) }
+
+    fn with_clock(clock: impl Clock + 'static) -> Self { Self { clock: Box::new(clock) } }
+
+    fn route(&self, vars: AgiVars, w: &mut impl Write) -> io::Result<Outcome> {
+        match self.lookup(vars, w).and_then(|()| w.flush()) {
+            Ok(()) => Ok(Outcome::Done),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(Outcome::PeerClosed),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn lookup(&self, vars: AgiVars, w: &mut impl Write) -> io::Result<()> {
+        if vars.is_health_probe() {
+            return set_var(w, "HEALTH", health(check_config));
+        }
+        set_var(w, "ROUTER_CALL_ID", &vars.call_id)?;
+        if let Some(sandbox) = TEST_REDIRECT {
+            return self.sandboxed(vars, sandbox, w).map(drop);
+        }
+        if let (Some(Mode::Outbound), Some(&(app, args))) = (vars.mode, FEATURE_CODES.get(&feature_code(&vars.dialed, DIAL_KEEP_CHARS))) {
+            decision!(app, "feature code answered by the router");
+            return run_feature_code(w, app, args);
+        }
+        let trace = EMIT_TRACE.then(|| trace_prefix(&vars));
+        let status = self.resolve(vars, w)?;
+        if let Some(mut trace) = trace {
+            trace_status(&mut trace, &status);
+            set_var(w, "ROUTER_TRACE", &trace)?;
+        }
+        set_lookup(status, w)
+    }
+
+    fn resolve(&self, vars: AgiVars, w: &mut impl Write) -> io::Result<LookupStatus<'static>> {
+        self.resolve_with(vars, false, w)
+    }
+
+    fn resolve_with(&self, vars: AgiVars, dry_run: bool, w: &mut impl Write) -> io::Result<LookupStatus<'static>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("route", mode = ?vars.mode, call_id = %vars.call_id).entered();
+        let status = match vars.mode {
+            Some(Mode::Outbound) => handle_outbound(vars, self.clock.now(), VERBOSE_VARS, MALFORMED_CALLER_POLICY, false, dry_run, w)?,
+            Some(Mode::Fallback) => handle_outbound(vars, self.clock.now(), VERBOSE_VARS, MALFORMED_CALLER_POLICY, true, dry_run, w)?,
+            Some(Mode::Inbound) => handle_inbound(vars, EMIT_CALLER_EXTENSION, w)?,
+            Some(Mode::Device) => handle_device(&vars, w)?,
+            Some(Mode::FollowMe) => handle_follow_me(&vars, &FOLLOW_ME, self.clock.now(), dry_run, w)?,
+            None => LookupStatus::Failure(FailureReason::BadMode),
+        };
+        decision!(?status, "resolved");
+        Ok(status)
+    }
+
+    /// Test mode: answers with `sandbox` and TEST_MODE=TRUE, and logs and
+    /// returns the decision a real lookup would have made. That decision is
+    /// a dry run and its routing variables (trunk, dial string) are dropped,
+    /// so nothing is dialed out.
+    fn sandboxed(&self, vars: AgiVars, sandbox: &'static str, w: &mut impl Write) -> io::Result<LookupStatus<'static>> {
+        let call_id = vars.call_id.clone();
+        let real = self.resolve_with(vars, true, &mut io::sink())?;
+        warning!("fastagi_router[{}]: test mode, real decision {:?}", call_id, real);
+        set_var(w, "TEST_MODE", flag(true))?;
+        set_lookup(LookupStatus::Internal(sandbox), w)?;
+        Ok(real)
+    }
+}
+
+fn trace_prefix(vars: &AgiVars) -> String {
+    let mode = match vars.mode {
+        Some(Mode::Outbound) => "outbound",
+        Some(Mode::Inbound) => "inbound",
+        Some(Mode::Fallback) => "fallback",
+        Some(Mode::Device) => "device",
+        Some(Mode::FollowMe) => "followme",
+        None => "bad",
+    };
+    let norm = match vars.mode {
+        Some(Mode::Inbound) => just_sanitize(&vars.dialed),
+        _ => sanitize_and_normalize(split_extension(&vars.dialed).0),
+    };
+    format!("mode={};norm={}", mode, norm.as_deref().unwrap_or("-"))
+}
+
+fn trace_status(trace: &mut String, status: &LookupStatus) {
+    use std::fmt::Write as _;
+    let _ = match status {
+        LookupStatus::Internal(ext) => write!(trace, ";map=hit;ext={}", ext),
+        LookupStatus::External { target, .. } => write!(trace, ";map=miss;target={}", target),
+        LookupStatus::Failure(r) => write!(trace, ";reason={}", r.code()),
+        LookupStatus::Blocked(r) => write!(trace, ";blocked={}", r.code()),
+    };
+    if trace.len() > MAX_TRACE_LEN {
+        let mut end = MAX_TRACE_LEN;
+        while !trace.is_char_boundary(end) { end -= 1; }
+        trace.truncate(end);
+    }
+}
+
+/// Output buffer for --batch. Results are streamed, so memory stays bounded
+/// however slowly the consumer reads.
+const BATCH_BUFFER_BYTES: usize = 64 * 1024;
+/// Flush --batch output at least this often, so a downstream reader sees
+/// results while a long run is still going.
+const BATCH_FLUSH_LINES: usize = 1000;
+/// Report lines processed to stderr this often during --batch; 0 disables.
+const BATCH_PROGRESS_LINES: usize = 100_000;
+
+/// `--batch`: classify `dialed[<TAB>caller[<TAB>mode[<TAB>channel]]]` lines
+/// from stdin, printing `input<TAB>internal|external|failure|blocked<TAB>target-or-reason`.
+/// Each lookup is a dry run: a batch never fetches from the directory or
+/// writes to the normalization audit.
+fn run_batch(router: &Router, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    let mut processed = 0;
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let dialed = fields.next().unwrap_or("").trim();
+        if dialed.is_empty() { continue; }
+        let mut vars = AgiVars::new(dialed, fields.next().unwrap_or("").trim(), DEFAULT_MODE)
+            .with_call_id(&format!("batch-{}", n + 1));
+        vars.mode = Mode::from_str(fields.next().unwrap_or("").trim());
+        vars = vars.with_channel(fields.next().unwrap_or("").trim());
+        let (kind, value) = router.resolve_with(vars, true, &mut io::sink())?.summary();
+        let written = writeln!(out, "{}\t{}\t{}", dialed, kind, value)
+            .and_then(|()| if (processed + 1) % BATCH_FLUSH_LINES == 0 { out.flush() } else { Ok(()) });
+        if let Err(e) = written {
+            eprintln!("fastagi_router: batch output failed at input line {} after {} results", n + 1, processed);
+            return Err(e);
+        }
+        processed += 1;
+        if BATCH_PROGRESS_LINES != 0 && processed % BATCH_PROGRESS_LINES == 0 {
+            eprintln!("fastagi_router: batch: {} lines processed", processed);
+        }
+    }
+    out.flush()
+}
+
+/// Process exit codes seen by Asterisk. A peer that hung up mid-write is not
+/// an error worth logging, so it gets its own code and no stderr output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Exit { Success = 0, ParseError = 2, WriteError = 3, PeerClosed = 4, ConfigError = 5 }
+
+impl From<Exit> for ExitCode {
+    fn from(e: Exit) -> Self { ExitCode::from(e as u8) }
+}
+
+/// Number keys are compared against sanitized digits at runtime, so a key
+/// kept with its formatting (`7 923 525 3998`, `79,235,253,998`) would
+/// never match.
+fn number_keys() -> Vec<(&'static str, &'static str)> {
+    let mut keys: Vec<_> = NUMBER_TO_EXT.keys().map(|&k| ("NUMBER_TO_EXT", k)).collect();
+    keys.extend(NUMBER_OVERRIDES.keys().map(|&k| ("NUMBER_OVERRIDES", k)));
+    keys.extend(ROUTE_METADATA.keys().map(|&k| ("ROUTE_METADATA", k)));
+    keys.extend(DID_RECORDING.keys().map(|&k| ("DID_RECORDING", k)));
+    keys.extend(DID_LANGUAGE.keys().map(|&k| ("DID_LANGUAGE", k)));
+    keys.extend(REPORT_AS_INTERNAL.iter().map(|&k| ("REPORT_AS_INTERNAL", k)));
+    keys.extend(DIAL_SEQUENCES.keys().map(|&k| ("DIAL_SEQUENCES", k)));
+    keys.extend(DID_GROUPS.iter().flat_map(|g| g.dids).map(|&k| ("DID_GROUPS", k)));
+    keys.extend(NO_DIAL.iter().map(|&k| ("NO_DIAL", k)));
+    keys.extend(TECH_ROUTES.iter().map(|r| ("TECH_ROUTES", r.did)));
+    keys
+}
+
+fn check_number_keys(keys: &[(&str, &str)]) -> Result<(), String> {
+    for &(table, key) in keys {
+        if just_sanitize(key).as_deref() != Some(key) {
+            return Err(format!("{} number {:?} is not a plain digit string of at most {} digits", table, key, MAX_DIGITS));
+        }
+    }
+    Ok(())
+}
+
+/// A trunk's tech and template, and every caller ID it may present in its
+/// format; `presented` is the owning extension's own DID, if any.
+fn check_trunk(t: &Trunk, owner: &str, presented: Option<&str>) -> Result<(), String> {
+    if t.tech.is_empty() || !t.target_template.contains("{target}") {
+        return Err(format!("trunk {} for {} has no tech or {{target}} in its template", t.name, owner));
+    }
+    for number in [Some(t.name), t.night_callerid, presented].into_iter().flatten() {
+        if t.callerid_format.apply(number).is_none() {
+            return Err(format!("caller ID {} on trunk {} cannot be written in the trunk's format", number, t.name));
+        }
+    }
+    Ok(())
+}
+
+fn check_config() -> Result<(), String> {
+    check_number_keys(&number_keys())?;
+    for (ext, t) in EXT_TO_TRUNK.entries() {
+        check_trunk(t, &format!("extension {}", ext), PRESENTED_DIDS.get(ext).copied())?;
+    }
+    for (ext, t) in FALLBACK_TRUNKS.entries() {
+        check_trunk(t, &format!("fallback extension {}", ext), PRESENTED_DIDS.get(ext).copied())?;
+    }
+    if let Some(t) = &FALLBACK_TRUNK {
+        check_trunk(t, "FALLBACK_TRUNK", None)?;
+    }
+    if let Some(t) = &PREMIUM_TRUNK {
+        check_trunk(t, "PREMIUM_TRUNK", None)?;
+    }
+    if let Some(t) = &INTERNATIONAL_TRUNK {
+        check_trunk(t, "INTERNATIONAL_TRUNK", None)?;
+    }
+    for (lead, prefix) in SIX_DIGIT_PREFIX_BY_LEAD {
+        if lead.is_empty() || prefix.len() + 6 != 11 || !format!("{}{}", lead, prefix).bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("six-digit prefix {:?} for lead {:?} does not expand 6 digits to an 11-digit number", prefix, lead));
+        }
+    }
+    if let Some(middle) = SHORT_DIAL_MIDDLE {
+        if SIX_DIGIT_PREFIX.len() + middle.len() + 3 != 11 || !middle.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("SHORT_DIAL_MIDDLE {:?} does not expand 3 digits to an 11-digit number", middle));
+        }
+    }
+    if let Some(prefix) = SEVEN_DIGIT_PREFIX {
+        if prefix.len() + 7 != 11 || !prefix.starts_with(HOME_COUNTRY_CODE) || !prefix.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("SEVEN_DIGIT_PREFIX {:?} does not expand 7 digits to an 11-digit number", prefix));
+        }
+    }
+    for (did, lang) in DID_LANGUAGE.entries().map(|(d, &l)| (*d, l)).chain([("default", DEFAULT_LANGUAGE)]) {
+        if !KNOWN_LANGUAGES.contains(&lang) {
+            return Err(format!("language {:?} for {} is not one of {:?}", lang, did, KNOWN_LANGUAGES));
+        }
+    }
+    for number in EMERGENCY_NUMBERS {
+        if ext_for(number).is_some() || FEATURE_CODES.contains_key(number) {
+            return Err(format!("emergency number {} is also an internal route or feature code", number));
+        }
+    }
+    for number in ROUTE_METADATA.keys() {
+        if resolve_layers(&TABLES, number, INBOUND_LAYERS).is_none() {
+            return Err(format!("ROUTE_METADATA has notes for {}, which is not a route", number));
+        }
+    }
+    for (_, var) in ROUTE_METADATA_VARS {
+        if var.is_empty() || !var.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_') {
+            return Err(format!("route metadata variable {:?} is not a plain dialplan variable name", var));
+        }
+    }
+    let foreign_codes = FOREIGN_ELEVEN_CODES.iter().copied().map(|c| ("FOREIGN_ELEVEN_CODES", c));
+    let fallback_code = match FOREIGN_ELEVEN_POLICY {
+        ForeignElevenPolicy::Country(code) => Some(("FOREIGN_ELEVEN_POLICY", code)),
+        ForeignElevenPolicy::Reject => None,
+    };
+    for (table, code) in foreign_codes.chain(fallback_code) {
+        if code.is_empty() || code.len() > 3 || code.starts_with(HOME_COUNTRY_CODE) || code.starts_with('8') || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("{} country code {:?} is not a 1-3 digit foreign code", table, code));
+        }
+    }
+    for (what, sources) in [("dialed", SOURCES.dialed), ("caller", SOURCES.caller), ("mode", SOURCES.mode)] {
+        if sources.is_empty() || !sources.iter().all(|s| s.starts_with("agi_")) {
+            return Err(format!("{} sources {:?} must list one or more agi_* environment keys", what, sources));
+        }
+    }
+    if !DIAL_KEEP_CHARS.bytes().all(|b| b.is_ascii_punctuation()) {
+        return Err(format!("DIAL_KEEP_CHARS {:?} may only hold ASCII symbols such as * and #", DIAL_KEEP_CHARS));
+    }
+    if STEERING != Steering::Variables && !READ_REPLIES {
+        return Err("Goto/Gosub steering needs READ_REPLIES".to_owned());
+    }
+    for template in [STEER_INTERNAL, STEER_EXTERNAL, STEER_FAILURE] {
+        if template.split(',').count() != 3 {
+            return Err(format!("steering template {:?} is not context,exten,priority", template));
+        }
+    }
+    if !FEATURE_CODES.is_empty() && !READ_REPLIES {
+        return Err("FEATURE_CODES needs READ_REPLIES to run applications".to_owned());
+    }
+    for (code, (app, _)) in FEATURE_CODES.entries() {
+        if app.is_empty() || !app.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(format!("feature code {:?}: bad application name {:?}", code, app));
+        }
+    }
+    for (code, s) in REASON_STRINGS.entries() {
+        if !FailureReason::ALL.iter().any(|r| r.code() == *code) {
+            return Err(format!("REASON_STRINGS overrides unknown reason {:?}", code));
+        }
+        if s.is_empty() {
+            return Err(format!("REASON_STRINGS maps {:?} to an empty string", code));
+        }
+    }
+    if !SUB_EXTENSION_PAUSE.chars().all(|c| DIAL_SEQUENCE_CHARS.contains(c)) {
+        return Err(format!("SUB_EXTENSION_PAUSE {:?} may only contain {}", SUB_EXTENSION_PAUSE, DIAL_SEQUENCE_CHARS));
+    }
+    for (number, seq) in DIAL_SEQUENCES.entries() {
+        if !is_dial_sequence(seq) {
+            return Err(format!("dial sequence {:?} for {} may only contain {}", seq, number, DIAL_SEQUENCE_CHARS));
+        }
+    }
+    Ok(())
+}
+
+/// Non-fatal inconsistencies between the number and trunk maps.
+fn config_warnings() -> Vec<String> {
+    let routed = NUMBER_TO_EXT.values().chain(NUMBER_OVERRIDES.values()).copied().collect();
+    reachability_warnings(routed, EXT_TO_TRUNK.keys().copied().collect())
+}
+
+/// Warnings for `trunked` extensions no number routes to and for `routed`
+/// extensions without a trunk.
+fn reachability_warnings(mut routed: Vec<&str>, mut trunked: Vec<&str>) -> Vec<String> {
+    routed.sort_unstable();
+    routed.dedup();
+    trunked.sort_unstable();
+    let mut warnings = Vec::new();
+    for ext in trunked.iter().filter(|e| routed.binary_search(e).is_err()) {
+        warnings.push(format!("extension {} has a trunk but no number routes to it", ext));
+    }
+    for ext in routed.iter().filter(|e| trunked.binary_search(e).is_err()) {
+        warnings.push(format!("extension {} has inbound numbers but no trunk", ext));
+    }
+    warnings
+}
+
+/// HEALTH for a probe: OK if `check` (check_config) passes, FAIL otherwise.
+fn health(check: impl FnOnce() -> Result<(), String>) -> &'static str {
+    if check().is_ok() { "OK" } else { "FAIL" }
+}
+
+fn run_check_config() -> Exit {
+    if let Err(e) = check_config() {
+        eprintln!("config error: {}", e);
+        return Exit::ConfigError;
+    }
+    for warning in config_warnings() {
+        eprintln!("warning: {}", warning);
+    }
+    println!("config ok");
+    Exit::Success
+}
+
+/// One AGI request: read the environment from `input` and answer on the
+/// writer `open` returns for the call id, reading replies from `input` if
+/// `read_replies`. The compiled-in tables cannot change under a running
+/// binary, so the config is checked by health probes and --check-config,
+/// not on every call.
+fn run<W: Write>(
+    router: &Router,
+    mut input: impl BufRead,
+    open: impl FnOnce(&str) -> W,
+    read_replies: bool,
+) -> Exit {
+    let vars = match AgiVars::from_reader(&mut input, &SOURCES) {
+        Ok(v) => v,
+        Err(e) => {
+            warning!("fastagi_router: failed to read AGI environment: {}", e);
+            return Exit::ParseError;
+        }
+    };
+    let call_id = vars.call_id.clone();
+    let mut out = open(&call_id);
+    let result = if read_replies {
+        router.route(vars, &mut AgiChannel::new(call_id.clone(), input, &mut out))
+    } else {
+        router.route(vars, &mut out)
+    };
+    match result {
+        Ok(Outcome::Done) => Exit::Success,
+        Ok(Outcome::PeerClosed) => Exit::PeerClosed,
+        Err(e) => {
+            warning!("fastagi_router[{}]: failed to write AGI commands: {}", call_id, e);
+            Exit::WriteError
+        }
+    }
+}
+
+/// `--batch [--at <unix-seconds>]`: the instant to evaluate every line as
+/// of, `Some(None)` for the system clock, `None` for bad arguments.
+fn batch_at(args: &[String]) -> Option<Option<i64>> {
+    match args {
+        [] => Some(None),
+        [flag, t] if flag == "--at" => t.parse().ok().map(Some),
+        _ => None,
+    }
+}
+
+/// `--classify <number> [--caller <caller>] [--mode <mode>]`: resolves one
+/// number, prints it like a `--batch` line and exits 0 for internal, 1 for
+/// external and 2 for a failure or block (2 is also a usage error).
+fn run_classify(args: &[String]) -> ExitCode {
+    classify(&Router::new(), args, &mut stdout().lock())
+}
+
+/// `--classify` against `router`, printing to `out`. The lookup is a dry
+/// run, so classifying a number leaves no directory fetch or audit entry.
+fn classify(router: &Router, args: &[String], out: &mut impl Write) -> ExitCode {
+    let usage = || {
+        eprintln!("fastagi_router: usage: --classify <number> [--caller <caller>] [--mode <mode>]");
+        Exit::ParseError.into()
+    };
+    let Some((dialed, mut rest)) = args.split_first() else { return usage() };
+    let (mut caller, mut mode) = ("", "");
+    while let [flag, value, tail @ ..] = rest {
+        match flag.as_str() {
+            "--caller" => caller = value,
+            "--mode" => mode = value,
+            _ => return usage(),
+        }
+        rest = tail;
+    }
+    if !rest.is_empty() { return usage(); }
+    let mut vars = AgiVars::new(dialed.as_str(), caller, DEFAULT_MODE).with_call_id("classify");
+    vars.mode = Mode::from_str(mode);
+    let status = match router.resolve_with(vars, true, &mut io::sink()) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("fastagi_router: classify failed: {}", e);
+            return Exit::WriteError.into();
+        }
+    };
+    let code = match status {
+        LookupStatus::Internal(_) => 0,
+        LookupStatus::External { .. } => 1,
+        LookupStatus::Failure(_) | LookupStatus::Blocked(_) => 2,
+    };
+    let (kind, value) = status.summary();
+    match writeln!(out, "{}\t{}\t{}", dialed, kind, value) {
+        Ok(()) => ExitCode::from(code),
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Exit::PeerClosed.into(),
+        Err(_) => Exit::WriteError.into(),
+    }
+}
+
+/// Just enough JSON for `--dump-routes`. Objects keep insertion order, so
+/// callers sort map entries to get a stable, diffable document.
+enum Json { Null, Bool(bool), Num(i64), Str(String), Arr(Vec<Json>), Obj(Vec<(String, Json)>) }
+
+impl Json {
+    fn str(s: &str) -> Self { Self::Str(s.to_owned()) }
+
+    fn opt_str(s: Option<&str>) -> Self { s.map_or(Self::Null, Self::str) }
+
+    fn strs<'a>(items: impl IntoIterator<Item = &'a str>) -> Self {
+        Self::Arr(items.into_iter().map(Self::str).collect())
+    }
+
+    fn obj<'a>(entries: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Self::Obj(entries.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    /// Object from map entries, sorted by key.
+    fn map<'a>(entries: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by_key(|&(k, _)| k);
+        Self::obj(entries)
+    }
+
+    fn trunk(t: &Trunk) -> Self {
+        Self::obj([
+            ("name", Self::str(t.name)),
+            ("tech", Self::str(t.tech)),
+            ("target_template", Self::str(t.target_template)),
+            ("options", Self::str(t.options)),
+            ("night_callerid", Self::opt_str(t.night_callerid)),
+            ("outbound_prefix", Self::str(t.outbound_prefix)),
+            ("callerid_format", Self::Str(format!("{:?}", t.callerid_format).to_ascii_lowercase())),
+        ])
+    }
+
+    fn write(&self, out: &mut String, indent: usize) {
+        use std::fmt::Write as _;
+        let pad = |out: &mut String, n: usize| out.extend(std::iter::repeat_n("  ", n));
+        match self {
+            Self::Null => out.push_str("null"),
+            Self::Bool(b) => { let _ = write!(out, "{}", b); }
+            Self::Num(n) => { let _ = write!(out, "{}", n); }
+            Self::Str(s) => {
+                out.push('"');
+                for c in s.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        c if c.is_control() => { let _ = write!(out, "\\u{:04x}", c as u32); }
+                        c => out.push(c),
+                    }
                 }
-                Some(d) => d,
-            };
-            match NUMBER_TO_EXT.get(&dialed) {
-                Some(&ext) => LookupStatus::Internal(ext),
-                None => LookupStatus::Failure("unknown_inbound_did"),
+                out.push('"');
+            }
+            Self::Arr(items) if items.is_empty() => out.push_str("[]"),
+            Self::Obj(entries) if entries.is_empty() => out.push_str("{}"),
+            Self::Arr(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(if i == 0 { "\n" } else { ",\n" });
+                    pad(out, indent + 1);
+                    item.write(out, indent + 1);
+                }
+                out.push('\n');
+                pad(out, indent);
+                out.push(']');
+            }
+            Self::Obj(entries) => {
+                out.push('{');
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    out.push_str(if i == 0 { "\n" } else { ",\n" });
+                    pad(out, indent + 1);
+                    Self::str(k).write(out, indent + 1);
+                    out.push_str(": ");
+                    v.write(out, indent + 1);
+                }
+                out.push('\n');
+                pad(out, indent);
+                out.push('}');
             }
         }
+    }
+}
+
+/// `--dump-routes`: the routing tables exactly as the router consults them,
+/// as one JSON document with sorted keys for diffing between deployments.
+fn dump_routes() -> Json {
+    let lower = |d: &dyn std::fmt::Debug| Json::Str(format!("{:?}", d).to_ascii_lowercase());
+    let effective = NUMBER_TO_EXT.keys().chain(NUMBER_OVERRIDES.keys())
+        .filter_map(|&n| ext_for(n).map(|e| (n, Json::str(e))))
+        .collect::<std::collections::BTreeMap<_, _>>();
+    Json::obj([
+        ("layers", Json::obj([
+            ("outbound", Json::strs(OUTBOUND_LAYERS.iter().map(|l| l.name()))),
+            ("inbound", Json::strs(INBOUND_LAYERS.iter().map(|l| l.name()))),
+        ])),
+        ("number_to_ext", Json::map(effective)),
+        ("number_overrides", Json::map(NUMBER_OVERRIDES.entries().map(|(&n, &e)| (n, Json::str(e))))),
+        ("ext_to_trunk", Json::map(EXT_TO_TRUNK.entries().map(|(&e, t)| (e, Json::trunk(t))))),
+        ("fallback_trunks", Json::map(FALLBACK_TRUNKS.entries().map(|(&e, t)| (e, Json::trunk(t))))),
+        ("fallback_trunk", FALLBACK_TRUNK.as_ref().map_or(Json::Null, Json::trunk)),
+        ("local", Json::obj([
+            ("six_digit_prefix", Json::str(SIX_DIGIT_PREFIX)),
+            ("six_digit_prefix_by_lead", Json::obj(SIX_DIGIT_PREFIX_BY_LEAD.iter().map(|&(l, p)| (l, Json::str(p))))),
+            ("seven_digit_prefix", Json::opt_str(SEVEN_DIGIT_PREFIX)),
+            ("short_dial_middle", Json::opt_str(SHORT_DIAL_MIDDLE)),
+            ("full_numbers_dial_external", Json::Bool(FULL_NUMBERS_DIAL_EXTERNAL)),
+        ])),
+        ("premium", Json::obj([
+            ("prefixes", Json::strs(PREMIUM_PREFIXES.iter().copied())),
+            ("trunk", PREMIUM_TRUNK.as_ref().map_or(Json::Null, Json::trunk)),
+        ])),
+        ("international", Json::obj([
+            ("access_prefixes", Json::strs(INTERNATIONAL_ACCESS_PREFIXES.iter().copied())),
+            ("min_length", Json::Num(MIN_INTERNATIONAL_LEN as i64)),
+            ("route_e164", Json::Bool(ROUTE_E164_INTERNATIONAL)),
+            ("trunk", INTERNATIONAL_TRUNK.as_ref().map_or(Json::Null, Json::trunk)),
+        ])),
+        ("route_metadata", Json::obj([
+            ("vars", Json::obj(ROUTE_METADATA_VARS.iter().map(|&(k, v)| (k, Json::str(v))))),
+            ("routes", Json::map(ROUTE_METADATA.entries().map(|(&n, fields)| {
+                (n, Json::obj(fields.iter().map(|&(k, v)| (k, Json::str(v)))))
+            }))),
+        ])),
+        ("foreign_eleven", Json::obj([
+            ("codes", Json::strs(FOREIGN_ELEVEN_CODES.iter().copied())),
+            ("policy", match FOREIGN_ELEVEN_POLICY {
+                ForeignElevenPolicy::Reject => Json::str("reject"),
+                ForeignElevenPolicy::Country(code) => Json::obj([("country", Json::str(code))]),
+            }),
+        ])),
+        ("emergency_numbers", Json::strs(EMERGENCY_NUMBERS.iter().copied())),
+        ("clir_extensions", { let mut e: Vec<_> = CLIR_EXTENSIONS.iter().copied().collect(); e.sort_unstable(); Json::strs(e) }),
+        ("blocked_prefixes", Json::strs(BLOCKED_PREFIXES.iter().copied())),
+        ("block_exceptions", Json::strs(BLOCK_EXCEPTIONS.iter().copied())),
+        ("no_dial", { let mut n: Vec<_> = NO_DIAL.iter().copied().collect(); n.sort_unstable(); Json::strs(n) }),
+        ("permissions", Json::map(EXT_PERMISSIONS.entries().map(|(&e, s)| (e, lower(s))))),
+        ("default_permission", lower(&DEFAULT_PERMISSION)),
+        ("max_call_seconds", Json::obj([
+            ("extensions", Json::map(EXT_MAX_CALL_SECONDS.entries().map(|(&e, &secs)| (e, Json::Num(secs.into()))))),
+            ("scopes", Json::Arr(SCOPE_MAX_CALL_SECONDS.iter().map(|(s, secs)| Json::obj([
+                ("scope", lower(s)),
+                ("seconds", Json::Num((*secs).into())),
+            ])).collect())),
+            ("default", DEFAULT_MAX_CALL_SECONDS.map_or(Json::Null, |secs| Json::Num(secs.into()))),
+        ])),
+        ("scope", Json::obj([
+            ("local_prefixes", Json::strs(LOCAL_PREFIXES.iter().copied())),
+            ("home_country_code", Json::str(HOME_COUNTRY_CODE)),
+            ("national_length", Json::Num(NATIONAL_LENGTH as i64)),
+        ])),
+        ("follow_me", Json::map(FOLLOW_ME.entries().map(|(&e, steps)| (e, Json::Arr(steps.iter().map(|s| Json::obj([
+            ("target", Json::str(s.target)),
+            ("timeout_secs", Json::Num(s.timeout_secs.into())),
+        ])).collect()))))),
+        ("did_language", Json::obj([
+            ("dids", Json::map(DID_LANGUAGE.entries().map(|(&d, &l)| (d, Json::str(l))))),
+            ("default", Json::str(DEFAULT_LANGUAGE)),
+        ])),
+        ("did_groups", Json::Arr(TABLES.groups.iter().map(|g| Json::obj([
+            ("name", Json::str(g.name)),
+            ("dids", Json::strs(g.dids.iter().copied())),
+            ("target", Json::str(g.target)),
+        ])).collect())),
+        ("did_suffix_digits", TABLES.suffix_digits.map_or(Json::Null, |n| Json::Num(n as i64))),
+        ("channel_techs", Json::Arr(CHANNEL_TECHS.iter().map(|&(prefix, tech)| Json::obj([
+            ("prefix", Json::str(prefix)),
+            ("tech", Json::str(tech)),
+        ])).collect())),
+        ("tech_routes", Json::Arr(TECH_ROUTES.iter().map(|r| Json::obj([
+            ("tech", Json::str(r.tech)),
+            ("did", Json::str(r.did)),
+            ("target", Json::str(r.target)),
+        ])).collect())),
+    ])
+}
+
+fn run_dump_routes() -> Exit {
+    let mut out = String::new();
+    dump_routes().write(&mut out, 0);
+    out.push('\n');
+    match stdout().lock().write_all(out.as_bytes()) {
+        Ok(()) => Exit::Success,
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Exit::PeerClosed,
+        Err(_) => Exit::WriteError,
+    }
+}
+
+fn main() -> ExitCode {
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt().with_writer(io::stderr).with_ansi(false).with_max_level(tracing::Level::DEBUG).init();
+    match std::env::args().nth(1).as_deref() {
+        Some("--check-config") => return run_check_config().into(),
+        Some("--dump-routes") => return run_dump_routes().into(),
+        Some("--classify") => return run_classify(&std::env::args().skip(2).collect::<Vec<_>>()),
+        Some("--batch") => {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            let Some(at) = batch_at(&args) else {
+                eprintln!("fastagi_router: usage: --batch [--at <unix-seconds>]");
+                return Exit::ParseError.into();
+            };
+            let router = at.map_or_else(Router::new, |t| Router::with_clock(FixedClock(t)));
+            let mut out = io::BufWriter::with_capacity(BATCH_BUFFER_BYTES, stdout().lock());
+            return match run_batch(&router, io::stdin().lock(), &mut out) {
+                Ok(()) => Exit::Success,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Exit::PeerClosed,
+                Err(e) => {
+                    eprintln!("fastagi_router: batch failed: {}", e);
+                    Exit::WriteError
+                }
+            }.into();
+        }
+        _ => {}
+    }
+    let router = Router::new();
+    let open = |call_id: &str| {
+        io::BufWriter::new(Tee { call_id: call_id.to_owned(), primary: stdout().lock(), secondary: open_capture(call_id) })
     };
-    set_lookup(status, w)
+    run(&router, io::stdin().lock(), open, READ_REPLIES).into()
 }
 
-fn main() -> io::Result<()> {
-    let mut out = stdout().lock();
-    let vars = AgiVars::from_stdin()?;
-    run_lookup(vars, &mut out)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1596 @@
+use super::*;
+use std::io::Read;
+
+fn outbound(dialed: &str, caller: &str) -> AgiVars {
+    AgiVars::new(dialed, caller, Mode::Outbound).with_call_id("test")
+}
+
+fn inbound(did: &str, caller: &str) -> AgiVars {
+    AgiVars::new(did, caller, Mode::Inbound).with_call_id("test")
+}
+
+/// Monday 2024-01-01, 12:00 and 22:00 in BUSINESS_HOURS' zone (UTC+7).
+const MONDAY_NOON: i64 = 1_704_085_200;
+const MONDAY_NIGHT: i64 = 1_704_121_200;
+
+/// A router whose clock is stuck at `now`.
+fn at(now: i64) -> Router {
+    Router::with_clock(FixedClock(now))
+}
+
+/// A full lookup by `router`, as written to Asterisk.
+fn lookup_at(vars: AgiVars, router: &Router) -> String {
+    let mut out = Vec::new();
+    router.lookup(vars, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn lookup(vars: AgiVars) -> String {
+    lookup_at(vars, &at(MONDAY_NOON))
+}
+
+/// Last value set for `name`, quoted or not.
+fn var<'a>(out: &'a str, name: &str) -> Option<&'a str> {
+    out.lines().rev().find_map(|l| {
+        let value = l.strip_prefix("SET VARIABLE ")?.strip_prefix(name)?.strip_prefix(' ')?;
+        Some(value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value))
+    })
+}
+
+#[test]
+fn struct_trunk_renders_its_own_tech_template_and_options() {
+    let t = Trunk { tech: "SIP", target_template: "{trunk}-out/{target}", options: "Ttb(predial^s^1)", ..Trunk::bare("sbc1") };
+    assert_eq!(t.dial_string("73843601000"), "SIP/sbc1-out/73843601000");
+    assert_eq!(t.options, "Ttb(predial^s^1)");
+}
+
+#[test]
+fn bare_number_trunk_gets_the_defaults() {
+    let t = Trunk::bare("79235253998");
+    assert_eq!((t.tech, t.options), ("PJSIP", "Ttr"));
+    assert_eq!(t.dial_string("73843601000"), "PJSIP/73843601000@79235253998");
+    let out = lookup(outbound("73843601000", "501"));
+    assert_eq!(var(&out, "DIAL_TRUNK"), Some("79235253998"));
+    assert_eq!(var(&out, "DIAL_STRING"), Some("PJSIP/73843601000@79235253998"));
+    assert_eq!(var(&out, "DIAL_OPTIONS"), Some("Ttr"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("73843601000"));
+}
+
+const REQUEST: &str = "agi_arg_1: 73843601000\nagi_arg_2: 501\n\n";
+
+/// Fails every read, as a closed or broken stdin would.
+struct BrokenReader;
+
+impl Read for BrokenReader {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> { Err(io::ErrorKind::Other.into()) }
+}
+
+/// Fails every write with `kind`.
+struct BrokenWriter(io::ErrorKind);
+
+impl Write for BrokenWriter {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(self.0.into()) }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn answered_lookup_exits_0() {
+    let mut out = Vec::new();
+    assert_eq!(run(&at(MONDAY_NOON), io::Cursor::new(REQUEST), |_| &mut out, false), Exit::Success);
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(var(&out, "LOOKUP_SUCCESS"), Some("TRUE"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("73843601000"));
+    assert_eq!(ExitCode::from(Exit::Success), ExitCode::SUCCESS);
+}
+
+#[test]
+fn unreadable_environment_exits_2() {
+    let input = io::BufReader::new(BrokenReader);
+    assert_eq!(run(&at(MONDAY_NOON), input, |_| Vec::new(), false), Exit::ParseError);
+    assert_eq!(Exit::ParseError as u8, 2);
+}
+
+#[test]
+fn failed_write_exits_3() {
+    let mut out = BrokenWriter(io::ErrorKind::Other);
+    assert_eq!(run(&at(MONDAY_NOON), io::Cursor::new(REQUEST), |_| &mut out, false), Exit::WriteError);
+    assert_eq!(Exit::WriteError as u8, 3);
+}
+
+#[test]
+fn peer_closed_mid_write_exits_4() {
+    let mut out = BrokenWriter(io::ErrorKind::BrokenPipe);
+    assert_eq!(run(&at(MONDAY_NOON), io::Cursor::new(REQUEST), |_| &mut out, false), Exit::PeerClosed);
+    assert_eq!(Exit::PeerClosed as u8, 4);
+}
+
+#[test]
+fn shipped_config_passes_check_config() {
+    assert_eq!(check_config(), Ok(()));
+    assert_eq!(run_check_config(), Exit::Success);
+    assert_eq!(Exit::ConfigError as u8, 5);
+}
+
+/// Accepts `lines` lines, then fails every write as a hung-up peer would.
+struct HangsUpAfter { lines: usize, written: Vec<u8> }
+
+impl Write for HangsUpAfter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written.iter().filter(|&&b| b == b'\n').count() == self.lines {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn broken_pipe_mid_sequence_is_a_clean_peer_close() {
+    let mut w = HangsUpAfter { lines: 2, written: Vec::new() };
+    let outcome = at(MONDAY_NOON).route(outbound("73843601000", "501"), &mut w).unwrap();
+    assert!(matches!(outcome, Outcome::PeerClosed));
+    assert_eq!(String::from_utf8(w.written).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn other_write_errors_are_still_errors() {
+    assert!(at(MONDAY_NOON).route(outbound("73843601000", "501"), &mut BrokenWriter(io::ErrorKind::Other)).is_err());
+}
+
+fn set_as(value: &str, quoting: Quoting) -> String {
+    let mut out = Vec::new();
+    set_var_as(&mut out, "DIAL_TARGET", value, quoting).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn variables_are_quoted_by_default() {
+    assert!(DEFAULT_QUOTING == Quoting::Quoted);
+    assert!(lookup(outbound("73843601000", "501")).contains("SET VARIABLE DIAL_TARGET \"73843601000\"\n"));
+}
+
+#[test]
+fn unquoted_variable_is_sent_bare() {
+    assert_eq!(set_as("73843601000", Quoting::Unquoted), "SET VARIABLE DIAL_TARGET 73843601000\n");
+    assert_eq!(set_as("73843601000", Quoting::Quoted), "SET VARIABLE DIAL_TARGET \"73843601000\"\n");
+}
+
+#[test]
+fn unquoted_value_that_would_split_the_command_is_quoted_anyway() {
+    assert_eq!(set_as("a b", Quoting::Unquoted), "SET VARIABLE DIAL_TARGET \"a b\"\n");
+    assert_eq!(set_as("a\tb", Quoting::Unquoted), "SET VARIABLE DIAL_TARGET \"ab\"\n");
+    assert_eq!(set_as("", Quoting::Unquoted), "SET VARIABLE DIAL_TARGET \"\"\n");
+}
+
+const NIGHT_TRUNK: Trunk = Trunk { night_callerid: Some("73843600000"), ..Trunk::bare("79235253998") };
+
+#[test]
+fn open_hours_call_presents_the_day_caller_id() {
+    assert!(BUSINESS_HOURS.is_open(MONDAY_NOON));
+    assert_eq!(NIGHT_TRUNK.callerid(true, None), "79235253998");
+    assert_eq!(var(&lookup_at(outbound("73843601000", "501"), &at(MONDAY_NOON)), "DIAL_CALLERID"), Some("79235253998"));
+}
+
+#[test]
+fn closed_hours_call_presents_the_night_caller_id() {
+    assert!(!BUSINESS_HOURS.is_open(MONDAY_NIGHT));
+    assert_eq!(NIGHT_TRUNK.callerid(false, None), "73843600000");
+    // The shipped trunks have no night caller ID and present their own number.
+    assert_eq!(var(&lookup_at(outbound("73843601000", "501"), &at(MONDAY_NIGHT)), "DIAL_CALLERID"), Some("79235253998"));
+}
+
+#[test]
+fn emergency_call_presents_the_day_caller_id_at_night() {
+    assert!(!day_callerid(MONDAY_NIGHT, false));
+    assert!(day_callerid(MONDAY_NIGHT, true));
+    assert!(day_callerid(MONDAY_NOON, false));
+    assert_eq!(NIGHT_TRUNK.callerid(day_callerid(MONDAY_NIGHT, true), None), "79235253998");
+}
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+
+#[test]
+fn business_hours_open_on_the_opening_minute() {
+    let opening = MONDAY_NOON - 3 * HOUR;
+    assert!(!BUSINESS_HOURS.is_open(opening - 1));
+    assert!(BUSINESS_HOURS.is_open(opening));
+}
+
+#[test]
+fn business_hours_close_on_the_closing_minute() {
+    let closing = MONDAY_NOON + 6 * HOUR;
+    assert!(BUSINESS_HOURS.is_open(closing - MINUTE));
+    assert!(!BUSINESS_HOURS.is_open(closing));
+}
+
+#[test]
+fn business_hours_are_closed_at_the_weekend() {
+    assert!(BUSINESS_HOURS.is_open(MONDAY_NOON + 4 * 24 * HOUR));
+    assert!(!BUSINESS_HOURS.is_open(MONDAY_NOON + 5 * 24 * HOUR));
+    assert!(!BUSINESS_HOURS.is_open(MONDAY_NOON + 6 * 24 * HOUR));
+}
+
+/// The target or reason a lookup reports.
+fn status(vars: AgiVars) -> String {
+    let out = lookup(vars);
+    let reason = var(&out, "LOOKUP_REASON").unwrap_or_default();
+    var(&out, "DIAL_TARGET").filter(|t| !t.is_empty()).unwrap_or(reason).to_owned()
+}
+
+// 79235254061 is 502's inbound DID and an ordinary dialable number.
+#[test]
+fn full_number_that_is_also_a_did_routes_internally_by_default() {
+    assert!(!full_number_shadowed("79235254061", "79235254061", FULL_NUMBERS_DIAL_EXTERNAL));
+    assert_eq!(status(outbound("79235254061", "501")), "502");
+}
+
+#[test]
+fn full_number_is_shadowed_when_full_numbers_place_external() {
+    assert!(full_number_shadowed("79235254061", "79235254061", true));
+    assert!(full_number_shadowed("8 (923) 525-40-61", "79235254061", true));
+    assert!(!full_number_shadowed("135", "135", true));
+    assert!(!full_number_shadowed("602313", "73843602313", true));
+}
+
+#[test]
+fn full_local_number_routes_like_its_six_digit_form() {
+    assert!(!full_number_shadowed("73843602313", "73843602313", true));
+    assert_eq!(status(outbound("73843602313", "502")), status(outbound("602313", "502")));
+}
+
+#[test]
+fn formatted_number_is_stripped_to_its_digits() {
+    assert_eq!(just_sanitize("+7 (923) 525-39-98").as_deref(), Some("79235253998"));
+    assert!(matches!(just_sanitize("79235253998"), Some(Cow::Borrowed(_))));
+}
+
+#[test]
+fn more_than_fifteen_digits_is_not_a_number() {
+    assert_eq!(just_sanitize(&"1".repeat(MAX_DIGITS)).map(|d| d.len()), Some(MAX_DIGITS));
+    assert_eq!(just_sanitize(&"1".repeat(MAX_DIGITS + 1)), None);
+    // Two numbers pasted together.
+    assert_eq!(just_sanitize("8 923 525-39-98, 8 384 360-23-13"), None);
+    assert_eq!(status(outbound("89235253998 89235254061", "501")), "normalize_failed_wrong_length");
+}
+
+#[test]
+fn two_digit_caller_under_each_policy() {
+    assert_eq!(malformed_caller(CallerPolicy::Ignore, "test", "42"), None);
+    assert_eq!(malformed_caller(CallerPolicy::Warn, "test", "42"), None);
+    assert_eq!(malformed_caller(CallerPolicy::Reject, "test", "42"), Some(FailureReason::MalformedCaller));
+    // The shipped policy ignores it and places the call without a trunk.
+    assert_eq!(status(outbound("73843601000", "42")), "73843601000");
+}
+
+#[test]
+fn extension_and_full_callers_are_never_malformed() {
+    for caller in ["501", "+7 923 525-39-98", ""] {
+        assert_eq!(malformed_caller(CallerPolicy::Reject, "test", caller), None, "{:?}", caller);
+    }
+}
+
+const HEALTH_PROBE: &str = "agi_request: agi://10.0.0.5/health\nagi_arg_1: 73843601000\nagi_arg_2: 501\n\n";
+
+#[test]
+fn health_probe_is_answered_without_a_lookup() {
+    let mut out = Vec::new();
+    assert_eq!(run(&at(MONDAY_NOON), io::Cursor::new(HEALTH_PROBE), |_| &mut out, false), Exit::Success);
+    assert_eq!(String::from_utf8(out).unwrap(), "SET VARIABLE HEALTH \"OK\"\n");
+}
+
+#[test]
+fn health_probe_path_may_end_in_a_slash() {
+    let vars = AgiVars::from_reader(io::Cursor::new("agi_request: agi://lb/health/\n\n"), &SOURCES).unwrap();
+    assert!(vars.is_health_probe());
+    let vars = AgiVars::from_reader(io::Cursor::new("agi_request: agi://lb/router\n\n"), &SOURCES).unwrap();
+    assert!(!vars.is_health_probe());
+}
+
+const BAD_CONFIG: fn() -> Result<(), String> = || Err("trunk 1 for extension 501 has no tech".to_owned());
+
+#[test]
+fn health_probe_reports_a_config_error() {
+    assert_eq!(health(check_config), "OK");
+    assert_eq!(health(BAD_CONFIG), "FAIL");
+}
+
+#[test]
+fn inbound_call_from_one_of_our_numbers_is_flagged_internal() {
+    let out = lookup(inbound("79235253998", "8 (384) 360-17-73"));
+    assert_eq!(var(&out, "IS_INTERNAL_CALLER"), Some("TRUE"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("501"));
+}
+
+#[test]
+fn inbound_call_from_outside_is_not_flagged_internal() {
+    let out = lookup(inbound("79235253998", "73831234567"));
+    assert_eq!(var(&out, "IS_INTERNAL_CALLER"), Some("FALSE"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("501"));
+}
+
+/// An external dial of `target` over `trunk`, as written to Asterisk.
+fn external(target: &str, trunk: &Trunk) -> String {
+    external_with_dtmf(target, trunk, None)
+}
+
+fn external_with_dtmf(target: &str, trunk: &Trunk, dtmf: Option<&str>) -> String {
+    let mut out = Vec::new();
+    let status = place_external(target.to_owned(), Some(trunk), dtmf, false, &mut out).unwrap();
+    set_lookup(status, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn trunk_with_an_outbound_prefix_prepends_it_to_the_target() {
+    let t = Trunk { outbound_prefix: "*21", ..Trunk::bare("79235253998") };
+    let out = external("73843601000", &t);
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("*2173843601000"));
+    assert_eq!(var(&out, "DIAL_STRING"), Some("PJSIP/*2173843601000@79235253998"));
+}
+
+#[test]
+fn trunk_without_an_outbound_prefix_dials_the_number_as_normalized() {
+    let out = external("73843601000", &Trunk::bare("79235253998"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("73843601000"));
+    assert_eq!(var(&out, "DIAL_STRING"), Some("PJSIP/73843601000@79235253998"));
+}
+
+#[test]
+fn post_connect_dtmf_is_sent_with_the_dial() {
+    let out = external_with_dtmf("79235253998", &Trunk::bare("79235254061"), Some("ww501#"));
+    assert_eq!(var(&out, "DIAL_POST_DTMF"), Some("ww501#"));
+    assert_eq!(var(&out, "DIAL_OPTIONS"), Some("TtrD(ww501#)"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("79235253998"));
+}
+
+#[test]
+fn target_without_a_sequence_sends_no_dtmf() {
+    let out = external("79235253998", &Trunk::bare("79235254061"));
+    assert_eq!(var(&out, "DIAL_POST_DTMF"), None);
+    assert_eq!(var(&out, "DIAL_OPTIONS"), Some("Ttr"));
+}
+
+#[test]
+fn dial_sequences_hold_only_digits_symbols_and_pauses() {
+    assert!(is_dial_sequence("ww501#"));
+    assert!(is_dial_sequence(",,*9W1"));
+    assert!(!is_dial_sequence(""));
+    assert!(!is_dial_sequence("ww501;"));
+}
+
+fn env(lines: &str) -> io::Result<AgiVars> {
+    AgiVars::from_reader(io::Cursor::new(lines), &SOURCES)
+}
+
+#[test]
+fn absent_or_empty_mode_is_the_default() {
+    assert_eq!(env("agi_arg_1: 501\n\n").unwrap().mode, Some(DEFAULT_MODE));
+    assert_eq!(env("agi_arg_1: 501\nagi_arg_3: \n\n").unwrap().mode, Some(DEFAULT_MODE));
+    assert_eq!(DEFAULT_MODE, Mode::Outbound);
+}
+
+#[test]
+fn valid_mode_is_read() {
+    assert_eq!(env("agi_arg_1: 501\nagi_arg_3: inbound\n\n").unwrap().mode, Some(Mode::Inbound));
+    assert_eq!(env("agi_arg_1: 501\nagi_arg_3: outbound\n\n").unwrap().mode, Some(Mode::Outbound));
+}
+
+#[test]
+fn mistyped_mode_is_a_bad_mode() {
+    let vars = env("agi_arg_1: 501\nagi_arg_3: outbont\n\n").unwrap();
+    assert_eq!(vars.mode, None);
+    assert_eq!(status(vars), "bad_mode");
+}
+
+#[test]
+fn call_id_reaches_the_log_prefix_and_router_call_id() {
+    let vars = env("agi_uniqueid: 1704085200.42\nagi_arg_1: 73843601000\nagi_arg_2: 42\n\n").unwrap();
+    assert_eq!(vars.call_id, "1704085200.42");
+    let warning = malformed_caller_warning(&vars.call_id, &vars.caller);
+    assert!(warning.starts_with("fastagi_router[1704085200.42]: "), "{}", warning);
+    assert_eq!(var(&lookup(vars), "ROUTER_CALL_ID"), Some("1704085200.42"));
+}
+
+#[test]
+fn call_id_is_sanitized_for_set_variable() {
+    let vars = env("agi_uniqueid: 1704085200.42\" \\x\n\n").unwrap();
+    assert_eq!(vars.call_id, "1704085200.42x");
+}
+
+#[test]
+fn missing_call_id_is_generated() {
+    let (a, b) = (env("\n").unwrap().call_id, env("\n").unwrap().call_id);
+    assert_eq!(a.len(), 36);
+    assert_eq!(a.as_bytes()[14], b'4');
+    assert_ne!(a, b);
+}
+
+#[test]
+fn inconsistent_config_is_warned_about() {
+    let warnings = reachability_warnings(vec!["501", "502", "501"], vec!["503", "501"]);
+    assert_eq!(warnings, [
+        "extension 503 has a trunk but no number routes to it",
+        "extension 502 has inbound numbers but no trunk",
+    ]);
+}
+
+#[test]
+fn consistent_config_has_no_warnings() {
+    assert!(reachability_warnings(vec!["501", "502"], vec!["502", "501"]).is_empty());
+}
+
+#[test]
+fn batch_classifies_each_line() {
+    let mut out = Vec::new();
+    run_batch(&at(MONDAY_NOON), io::Cursor::new("111\t501\n\n 73843601000 \t501\toutbound\n111\t\tsideways\n"), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "111\tinternal\t508\n73843601000\texternal\t73843601000\n111\tfailure\tbad_mode\n");
+}
+
+/// Counts the writes and flushes that reach the underlying stream.
+#[derive(Default)]
+struct CountingWriter { writes: usize, flushes: usize }
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn lookup_output_is_written_and_flushed_once() {
+    let mut out = io::BufWriter::new(CountingWriter::default());
+    assert!(matches!(at(MONDAY_NOON).route(outbound("73843601000", "501"), &mut out), Ok(Outcome::Done)));
+    let counted = out.get_ref();
+    assert_eq!((counted.writes, counted.flushes), (1, 1));
+}
+
+#[test]
+fn batch_output_is_flushed_every_batch_flush_lines() {
+    let input = "501\t502\n".repeat(BATCH_FLUSH_LINES * 2 + 1);
+    let mut out = CountingWriter::default();
+    run_batch(&at(MONDAY_NOON), io::Cursor::new(input), &mut out).unwrap();
+    // Two periodic flushes and the final one.
+    assert_eq!(out.flushes, 3);
+}
+
+#[cfg(feature = "bench")]
+mod bench {
+    extern crate test;
+    use super::*;
+    use test::Bencher;
+
+    #[bench]
+    fn buffered_outbound_lookup(b: &mut Bencher) {
+        b.iter(|| at(MONDAY_NOON).route(outbound("73843601000", "501"), &mut io::BufWriter::new(io::sink())).unwrap());
+    }
+
+    #[bench]
+    fn buffered_inbound_lookup(b: &mut Bencher) {
+        b.iter(|| at(MONDAY_NOON).route(inbound("79235254061", "79001234567"), &mut io::BufWriter::new(io::sink())).unwrap());
+    }
+}
+
+#[test]
+fn withheld_and_empty_callers_are_anonymous() {
+    for caller in ["anonymous", "Restricted", " unknown ", ""] {
+        assert_eq!(var(&lookup(inbound("79235254061", caller)), "IS_ANONYMOUS"), Some("TRUE"), "{:?}", caller);
+    }
+    assert_eq!(var(&lookup(inbound("79235254061", "79001234567")), "IS_ANONYMOUS"), Some("FALSE"));
+}
+
+static RECORDING: phf::Map<&'static str, bool> = phf_map! {
+    "79235254132" => true, "79235254439" => false,
+};
+
+#[test]
+fn listed_dids_follow_their_recording_policy() {
+    assert!(should_record(&RECORDING, "79235254132"));
+    assert!(!should_record(&RECORDING, "79235254439"));
+}
+
+#[test]
+fn unlisted_did_gets_the_default_recording_policy() {
+    assert_eq!(should_record(&RECORDING, "79235253998"), RECORD_BY_DEFAULT);
+    let expected = if RECORD_BY_DEFAULT { "TRUE" } else { "FALSE" };
+    assert_eq!(var(&lookup(inbound("79235253998", "73831234567")), "SHOULD_RECORD"), Some(expected));
+}
+
+#[test]
+fn verbose_lookup_reports_dialed_original_and_normalized() {
+    let mut out = Vec::new();
+    handle_outbound(outbound("8 (384) 360-10-00", "501"), MONDAY_NOON, true, MALFORMED_CALLER_POLICY, false, false, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(var(&out, "DIALED_ORIGINAL"), Some("8 (384) 360-10-00"));
+    assert_eq!(var(&out, "DIALED_NORMALIZED"), Some("73843601000"));
+}
+
+#[test]
+fn quiet_lookup_omits_dialed_values() {
+    let mut out = Vec::new();
+    handle_outbound(outbound("8 (384) 360-10-00", "501"), MONDAY_NOON, false, MALFORMED_CALLER_POLICY, false, false, &mut out).unwrap();
+    assert!(!String::from_utf8(out).unwrap().contains("DIALED_"));
+}
+
+#[test]
+fn quoted_values_are_escaped() {
+    assert_eq!(set_as("say \"hi\"\\\n", Quoting::Quoted), "SET VARIABLE DIAL_TARGET \"say \\\"hi\\\"\\\\\"\n");
+    assert_eq!(set_as("a\\b", Quoting::Unquoted), "SET VARIABLE DIAL_TARGET \"a\\\\b\"\n");
+}
+
+#[test]
+fn reply_with_trailing_data_is_a_success() {
+    let mut r = io::Cursor::new("200 result=1 (extra data)\n");
+    assert_eq!(read_reply(&mut r).unwrap(), Reply::Ok);
+}
+
+#[test]
+fn multi_line_usage_reply_is_read_to_its_end() {
+    let mut r = io::Cursor::new("520-Invalid command syntax.\nUsage: SET VARIABLE\n520 End of proper usage.\n200 result=1\n");
+    assert_eq!(read_reply(&mut r).unwrap(), Reply::Failed(520));
+    assert_eq!(read_reply(&mut r).unwrap(), Reply::Ok);
+}
+
+#[test]
+fn closed_reply_stream_is_a_hangup() {
+    assert_eq!(read_reply(&mut io::Cursor::new("")).unwrap(), Reply::Hangup);
+}
+
+#[test]
+fn hangup_between_replies_ends_the_request_as_a_peer_close() {
+    let input = format!("{}200 result=1\n\nHANGUP\n200 result=1\n", REQUEST);
+    let mut out = Vec::new();
+    assert_eq!(run(&at(MONDAY_NOON), io::Cursor::new(input), |_| &mut out, true), Exit::PeerClosed);
+    let sent = String::from_utf8(out).unwrap();
+    assert_eq!(sent.lines().count(), 2, "{}", sent);
+}
+
+#[test]
+fn endless_environment_is_cut_off() {
+    let endless = "agi_foo: bar\n".repeat(MAX_ENV_LINES + 1);
+    assert_eq!(env(&endless).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn environment_of_exactly_the_line_bound_is_accepted() {
+    let full = format!("{}\n", "agi_foo: bar\n".repeat(MAX_ENV_LINES));
+    assert!(env(&full).is_ok());
+}
+
+#[test]
+fn oversized_environment_line_is_rejected() {
+    let line = format!("agi_arg_1: {}\n\n", "1".repeat(MAX_ENV_LINE_BYTES as usize));
+    let err = env(&line).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("bytes"), "{}", err);
+}
+
+#[test]
+fn unterminated_environment_ends_at_end_of_input() {
+    let vars = env("agi_arg_1: 89235253998\nagi_arg_2: 502").unwrap();
+    assert_eq!((vars.dialed.as_str(), vars.caller.as_str()), ("89235253998", "502"));
+}
+
+static PERMISSIONS: phf::Map<&'static str, Scope> = phf_map! { "503" => Scope::Local };
+
+#[test]
+fn external_numbers_are_scoped_by_reach() {
+    assert!(Scope::of("73843601000") == Scope::Local);
+    assert!(Scope::of("79231112233") == Scope::National);
+    assert!(Scope::of("4930123456") == Scope::International);
+    assert!(Scope::of("792311122334") == Scope::International);
+}
+
+#[test]
+fn listed_extension_gets_its_permission() {
+    assert!(permission(&PERMISSIONS, Some("503")) == Scope::Local);
+    assert!(permission(&PERMISSIONS, Some("501")) == DEFAULT_PERMISSION);
+    assert!(permission(&PERMISSIONS, None) == DEFAULT_PERMISSION);
+}
+
+#[test]
+fn local_only_extension_may_dial_local_and_emergency_numbers_only() {
+    assert!(!permitted(&PERMISSIONS, Some("503"), "79231112233"));
+    assert!(permitted(&PERMISSIONS, Some("503"), "73843601000"));
+    assert!(permitted(&PERMISSIONS, Some("503"), "112"));
+    assert!(Scope::of("112") > Scope::Local);
+}
+
+#[test]
+fn route_reported_as_internal_is_still_dialed_over_the_trunk() {
+    let mut out = Vec::new();
+    let status = place_external("73843601000".to_owned(), Some(&Trunk::bare("79235253998")), None, true, &mut out).unwrap();
+    set_lookup(status, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(var(&out, "IS_INTERNAL_DEST"), Some("TRUE"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("73843601000"));
+    assert!(var(&out, "DIAL_STRING").is_some_and(|d| d.contains("73843601000")), "{}", out);
+}
+
+#[test]
+fn ordinary_external_route_is_not_internal() {
+    assert_eq!(var(&external("73843601000", &Trunk::bare("79235253998")), "IS_INTERNAL_DEST"), Some("FALSE"));
+}
+
+#[test]
+fn unknown_short_dial_is_rejected_by_default() {
+    assert_eq!(expand_short_dial("999", SHORT_DIAL_MIDDLE), None);
+    assert_eq!(status(outbound("999", "501")), "short_internal_rejected");
+    assert_eq!(status(outbound("135", "501")), "502");
+}
+
+#[test]
+fn unknown_short_dial_expands_when_a_middle_segment_is_set() {
+    assert_eq!(expand_short_dial("999", Some("601")).as_deref(), Some("73843601999"));
+    // The expansion is routed like any other number, so it can land on an extension.
+    assert_eq!(ext_for(&expand_short_dial("773", Some("601")).unwrap()), Some("502"));
+}
+
+#[test]
+fn seven_digit_dial_is_rejected_unless_a_prefix_is_set() {
+    assert_eq!(expand_seven_digit("5253998", SEVEN_DIGIT_PREFIX), None);
+    assert_eq!(status(outbound("525-39-98", "501")), "normalize_failed_wrong_length");
+}
+
+#[test]
+fn seven_digit_dial_expands_under_its_prefix() {
+    assert_eq!(expand_seven_digit("5253998", Some("7923")).as_deref(), Some("79235253998"));
+    assert_eq!(ext_for(&expand_seven_digit("5253998", Some("7923")).unwrap()), Some("501"));
+}
+
+#[test]
+fn built_request_gets_a_generated_or_sanitized_call_id() {
+    let vars = AgiVars::new("501", "502", Mode::Outbound);
+    assert_eq!(vars.call_id.len(), 36);
+    assert_eq!(vars.with_call_id("batch 7\"").call_id, "batch7");
+}
+
+static REASONS: phf::Map<&'static str, &'static str> = phf_map! { "not_permitted" => "4" };
+
+#[test]
+fn overridden_reason_string_replaces_the_default() {
+    assert_eq!(FailureReason::NotPermitted.code(), "not_permitted");
+    assert_eq!(FailureReason::NotPermitted.as_str_in(&REASONS), "4");
+    assert_eq!(FailureReason::EmptyDial.as_str_in(&REASONS), "empty_dial");
+}
+
+#[test]
+fn every_reason_has_its_own_code() {
+    let mut codes: Vec<_> = FailureReason::ALL.iter().map(|r| r.code()).collect();
+    codes.sort_unstable();
+    codes.dedup();
+    assert_eq!(codes.len(), FailureReason::ALL.len());
+}
+
+#[test]
+fn presented_did_overrides_the_shared_trunk_caller_id() {
+    let shared = Trunk::bare("79235253998");
+    assert_eq!(shared.callerid(true, Some("79235254132")), "79235254132");
+    assert_eq!(shared.callerid(false, Some("79235254132")), "79235254132");
+    assert_eq!(shared.callerid(true, None), "79235253998");
+}
+
+#[test]
+fn night_caller_id_still_wins_out_of_hours() {
+    assert_eq!(NIGHT_TRUNK.callerid(false, Some("79235254132")), "73843600000");
+    assert_eq!(NIGHT_TRUNK.callerid(true, Some("79235254132")), "79235254132");
+}
+
+/// Everything `f` logs through `tracing`, as the fmt subscriber writes it.
+#[cfg(feature = "tracing")]
+fn traced(f: impl FnOnce()) -> String {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_max_level(tracing::Level::DEBUG)
+        .finish();
+    tracing::subscriber::with_default(subscriber, f);
+    let log = captured.0.lock().unwrap().clone();
+    String::from_utf8(log).unwrap()
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn decision_events_are_recorded_under_the_route_span() {
+    let log = traced(|| { lookup(outbound("8 (384) 360-10-00", "501")); });
+    assert!(log.contains("dialed number normalized"), "{}", log);
+    assert!(log.contains("normalized=73843601000"), "{}", log);
+    assert!(log.contains("call_id=test"), "{}", log);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn call_warnings_are_tracing_events() {
+    let log = traced(|| { malformed_caller(CallerPolicy::Warn, "test", "42"); });
+    assert!(log.contains("WARN"), "{}", log);
+    assert!(log.contains(&malformed_caller_warning("test", "42")), "{}", log);
+}
+
+#[test]
+fn premium_prefix_matches_the_sanitized_dial() {
+    let prefixes = &["8809", "0900"];
+    assert_eq!(premium_number("8 (809) 555-01-02", prefixes).as_deref(), Some("88095550102"));
+    assert_eq!(premium_number("0900 123456", prefixes).as_deref(), Some("0900123456"));
+    assert_eq!(premium_number("89231112233", prefixes), None);
+    assert_eq!(premium_number("89231112233", PREMIUM_PREFIXES), None);
+}
+
+#[test]
+fn destination_within_the_default_permission_is_dialed() {
+    let mut out = Vec::new();
+    let status = dial_external("test", "4930123456".to_owned(), EXT_TO_TRUNK.get("501"), Caller { ext: Some("501"), did: None }, None, MONDAY_NOON, &mut out).unwrap();
+    assert!(matches!(status, LookupStatus::External { .. }));
+}
+
+#[test]
+fn chosen_trunk_is_announced_once_with_its_own_caller_id() {
+    let mut out = Vec::new();
+    let caller = Caller { ext: Some("501"), did: None };
+    dial_external("test", "4930123456".to_owned(), Some(&SPARE), caller, None, MONDAY_NOON, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.matches("SET VARIABLE DIAL_TRUNK ").count(), 1);
+    assert_eq!(var(&out, "DIAL_TRUNK"), Some("73843600002"));
+    assert_eq!(var(&out, "DIAL_CALLERID"), Some("73843600002"));
+    let out = lookup(outbound("73843601000", "501"));
+    assert_eq!(out.matches("SET VARIABLE DIAL_TRUNK ").count(), 1);
+    assert_eq!(out.matches("SET VARIABLE DIAL_CALLERID ").count(), 1);
+}
+
+#[test]
+fn test_mode_answers_with_the_sandbox_and_keeps_the_real_decision() {
+    let mut out = Vec::new();
+    let real = at(MONDAY_NOON).sandboxed(outbound("73843601000", "501"), "599", &mut out).unwrap();
+    assert!(matches!(real, LookupStatus::External { ref target, .. } if target == "73843601000"));
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(var(&out, "TEST_MODE"), Some("TRUE"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("599"));
+    assert_eq!(var(&out, "IS_INTERNAL_DEST"), Some("TRUE"));
+    assert_eq!(var(&out, "DIAL_STRING"), None);
+    assert_eq!(var(&out, "DIAL_TRUNK"), None);
+}
+
+static NO_ROUTES: phf::Map<&'static str, &'static str> = phf::phf_map! {};
+static ROUTES_0505_TO_502: phf::Map<&'static str, &'static str> = phf::phf_map! { "79235250505" => "502" };
+
+/// The shipped tables with DID_GROUPS and DID_SUFFIX_DIGITS swapped out.
+fn tables(groups: &'static [DidGroup], suffix_digits: Option<usize>) -> Tables {
+    Tables { groups, suffix_digits, ..TABLES }
+}
+
+/// Inbound extension with DID suffixes tried after `exact`, and no groups.
+fn suffix_route(did: &str, exact: &'static phf::Map<&'static str, &'static str>, digits: Option<usize>) -> Option<&'static str> {
+    let tables = Tables { exact, ..tables(&[], digits) };
+    resolve_layers(&tables, did, INBOUND_LAYERS).map(|(_, ext)| ext)
+}
+
+#[test]
+fn unmapped_did_reaches_the_extension_in_its_suffix() {
+    assert_eq!(suffix_route("79235250505", &NUMBER_TO_EXT, Some(3)), Some("505"));
+    assert_eq!(ext_from_did_suffix("79235250505", 3), Some("505"));
+}
+
+#[test]
+fn did_suffix_that_names_no_extension_is_a_miss() {
+    assert_eq!(suffix_route("79235250599", &NUMBER_TO_EXT, Some(3)), None);
+    assert_eq!(suffix_route("79235250505", &NUMBER_TO_EXT, None), None);
+    assert_eq!(ext_from_did_suffix("05", 3), None);
+}
+
+#[test]
+fn explicit_did_entry_wins_over_its_suffix() {
+    assert_eq!(suffix_route("79235250505", &ROUTES_0505_TO_502, Some(3)), Some("502"));
+    assert_eq!(suffix_route("79235254061", &NUMBER_TO_EXT, Some(3)), Some("502"));
+}
+
+#[test]
+fn caller_id_is_written_in_each_format() {
+    assert_eq!(CallerIdFormat::Stored.apply("8 (923) 525-39-98").as_deref(), Some("8 (923) 525-39-98"));
+    assert_eq!(CallerIdFormat::International.apply("8 (923) 525-39-98").as_deref(), Some("79235253998"));
+    assert_eq!(CallerIdFormat::E164.apply("89235253998").as_deref(), Some("+79235253998"));
+    assert_eq!(CallerIdFormat::National.apply("+7 923 525 39 98").as_deref(), Some("9235253998"));
+}
+
+#[test]
+fn caller_id_that_is_no_full_national_number_has_no_format() {
+    assert_eq!(CallerIdFormat::E164.apply("501").as_deref(), None);
+    assert_eq!(CallerIdFormat::National.apply("").as_deref(), None);
+    assert_eq!(CallerIdFormat::Stored.apply("501").as_deref(), Some("501"));
+    // The trunk then presents the number as stored.
+    let t = Trunk { callerid_format: CallerIdFormat::E164, ..Trunk::bare("501") };
+    assert_eq!(t.callerid(true, None), "501");
+}
+
+#[test]
+fn uri_number_is_its_user_part() {
+    assert_eq!(uri_user("sip:501@pbx12"), "501");
+    assert_eq!(uri_user("tel:+79235253998;phone-context=x"), "+79235253998");
+    assert_eq!(uri_user("8 (923) 525-39-98"), "8 (923) 525-39-98");
+    assert_eq!(just_sanitize("sip:501@pbx12").as_deref(), Some("501"));
+    assert_eq!(status(outbound("sip:135@pbx12", "sip:501@pbx12")), "502");
+}
+
+const ACCESS_PREFIXES: &[&str] = &["810", "00"];
+
+#[test]
+fn access_prefix_is_stripped_from_international_dials() {
+    assert_eq!(strip_access_prefix("810493012345678", ACCESS_PREFIXES), Some("493012345678"));
+    assert_eq!(strip_access_prefix("00493012345678", ACCESS_PREFIXES), Some("493012345678"));
+    assert_eq!(strip_access_prefix("810123", ACCESS_PREFIXES), None);
+    assert_eq!(strip_access_prefix("810493012345678", INTERNATIONAL_ACCESS_PREFIXES), None);
+}
+
+#[test]
+fn national_eight_dial_never_looks_like_810() {
+    // Home area codes do not start with 0 or 1, so `8` + 10 digits is safe.
+    assert_eq!(strip_access_prefix("89231112233", ACCESS_PREFIXES), None);
+    assert_eq!(strip_access_prefix("81079231112233", ACCESS_PREFIXES).filter(|r| is_home_national(r)), Some("79231112233"));
+}
+
+/// ROUTER_TRACE as debug builds emit it; EMIT_TRACE is off under --release.
+fn trace(vars: AgiVars) -> String {
+    let mut trace = trace_prefix(&vars);
+    let status = at(MONDAY_NOON).resolve(vars, &mut io::sink()).unwrap();
+    trace_status(&mut trace, &status);
+    trace
+}
+
+#[test]
+fn trace_of_an_internal_decision() {
+    assert_eq!(trace(outbound("89235253998", "502")), "mode=outbound;norm=79235253998;map=hit;ext=501");
+}
+
+#[test]
+fn trace_of_an_external_decision() {
+    assert_eq!(trace(outbound("73843601000", "501")), "mode=outbound;norm=73843601000;map=miss;target=73843601000");
+}
+
+#[test]
+fn trace_of_a_failure() {
+    assert_eq!(trace(inbound("", "501")), "mode=inbound;norm=-;reason=empty_dial");
+}
+
+#[test]
+fn trace_normalizes_the_number_without_its_sub_extension() {
+    let trace = trace(outbound("73843601000x123", "501"));
+    assert!(trace.starts_with("mode=outbound;norm=73843601000;"), "{}", trace);
+}
+
+#[test]
+fn trace_is_cut_to_its_length_bound() {
+    let mut trace = "é".repeat(MAX_TRACE_LEN);
+    trace_status(&mut trace, &LookupStatus::Internal("501"));
+    assert!(trace.len() <= MAX_TRACE_LEN);
+}
+
+static SALES: &[DidGroup] = &[
+    DidGroup { name: "sales", dids: &["79235259000", "79235253998"], target: "queue-sales" },
+];
+
+#[test]
+fn individually_mapped_did_wins_over_its_group() {
+    let route = resolve_layers(&tables(SALES, None), "79235253998", INBOUND_LAYERS);
+    assert_eq!(route, Some((Layer::Exact, "501")));
+}
+
+#[test]
+fn group_only_did_goes_to_the_group_target() {
+    let sales = tables(SALES, Some(3));
+    assert_eq!(resolve_layers(&sales, "79235259000", INBOUND_LAYERS), Some((Layer::Group, "queue-sales")));
+    assert_eq!(sales.did_group("79235259000").map(|g| g.name), Some("sales"));
+    assert_eq!(resolve_layers(&TABLES, "79235259000", INBOUND_LAYERS), None);
+}
+
+static OVERRIDE_0505: phf::Map<&'static str, &'static str> = phf::phf_map! { "79235250505" => "509" };
+static GROUP_0505: &[DidGroup] = &[
+    DidGroup { name: "support", dids: &["79235250505"], target: "queue-support" },
+];
+
+#[test]
+fn overlapping_entries_resolve_to_the_first_layer() {
+    let all = Tables { overrides: &OVERRIDE_0505, exact: &ROUTES_0505_TO_502, groups: GROUP_0505, suffix_digits: Some(3) };
+    assert_eq!(resolve_layers(&all, "79235250505", INBOUND_LAYERS), Some((Layer::Override, "509")));
+    let no_override = Tables { overrides: &NO_ROUTES, ..all };
+    assert_eq!(resolve_layers(&no_override, "79235250505", INBOUND_LAYERS), Some((Layer::Exact, "502")));
+    let group_and_suffix = Tables { exact: &NO_ROUTES, ..no_override };
+    assert_eq!(resolve_layers(&group_and_suffix, "79235250505", INBOUND_LAYERS), Some((Layer::Group, "queue-support")));
+    let suffix_only = Tables { groups: &[], ..group_and_suffix };
+    assert_eq!(resolve_layers(&suffix_only, "79235250505", INBOUND_LAYERS), Some((Layer::DidSuffix, "505")));
+    assert_eq!(resolve_layers(&all, "79235250505", OUTBOUND_LAYERS), Some((Layer::Override, "509")));
+    assert_eq!(resolve_layers(&group_and_suffix, "79235250505", OUTBOUND_LAYERS), None);
+}
+
+#[test]
+fn e164_home_number_routes_like_its_national_form() {
+    assert_eq!(status(outbound("+79235253998", "502")), "501");
+    assert_eq!(status(outbound("+7 384 360-10-00", "501")), "73843601000");
+}
+
+#[test]
+fn e164_foreign_number_is_not_misread_as_a_home_number() {
+    assert_eq!(sanitize_and_normalize("+89235253998"), None);
+    assert_eq!(sanitize_and_normalize("+849235253998"), None);
+    assert_eq!(status(outbound("+4930123456", "501")), "foreign_number");
+    assert_eq!(status(outbound("+19995551234", "501")), "foreign_number");
+}
+
+#[test]
+fn short_e164_number_is_a_length_failure() {
+    assert_eq!(status(outbound("+12345", "501")), "normalize_failed_wrong_length");
+}
+
+#[test]
+fn e164_foreign_number_goes_international_when_enabled() {
+    assert_eq!(international_number("+4930123456", ACCESS_PREFIXES, true).as_deref(), Some("4930123456"));
+    assert_eq!(international_number("+4930123456", ACCESS_PREFIXES, false), None);
+    assert_eq!(international_number("+79235253998", ACCESS_PREFIXES, true), None);
+    assert_eq!(international_number("00 49 30 123456", ACCESS_PREFIXES, false).as_deref(), Some("4930123456"));
+}
+
+static EXT_LIMITS: phf::Map<&'static str, u32> = phf_map! { "503" => 600 };
+const SCOPE_LIMITS: &[(Scope, u32)] = &[(Scope::International, 1800)];
+
+#[test]
+fn extension_call_limit_wins_over_the_scope_limit() {
+    assert_eq!(max_call_seconds(&EXT_LIMITS, SCOPE_LIMITS, Some(3600), Some("503"), "4930123456"), Some(600));
+    assert_eq!(max_call_seconds(&EXT_LIMITS, SCOPE_LIMITS, Some(3600), Some("501"), "4930123456"), Some(1800));
+}
+
+#[test]
+fn unlimited_call_gets_the_default_or_no_hint() {
+    assert_eq!(max_call_seconds(&EXT_LIMITS, SCOPE_LIMITS, Some(3600), Some("501"), "79231112233"), Some(3600));
+    assert_eq!(max_call_seconds(&EXT_LIMITS, SCOPE_LIMITS, None, None, "73843601000"), None);
+    // The shipped config sets no limits, so no hint is emitted.
+    assert_eq!(var(&lookup(outbound("73843601000", "501")), "MAX_CALL_SECONDS"), None);
+}
+
+#[test]
+fn emergency_call_never_carries_a_limit() {
+    assert_eq!(Scope::of("112"), Scope::International);
+    assert_eq!(max_call_seconds(&EXT_LIMITS, SCOPE_LIMITS, Some(3600), Some("503"), "112"), None);
+    assert_eq!(max_call_seconds(&EXT_LIMITS, SCOPE_LIMITS, Some(3600), Some("501"), "112"), None);
+    assert_eq!(max_call_seconds(&EXT_LIMITS, SCOPE_LIMITS, Some(3600), None, "112"), None);
+}
+
+#[test]
+fn caller_dialing_its_own_number_is_a_self_call() {
+    assert_eq!(status(outbound("79161234567", "89161234567")), "self_call");
+    assert_eq!(status(inbound("79235253998", "+79235253998")), "self_call");
+}
+
+#[test]
+fn caller_dialing_another_number_is_no_self_call() {
+    assert_eq!(status(outbound("79161234567", "79161234568")), "79161234567");
+    assert_eq!(status(inbound("79235253998", "79161234567")), "501");
+}
+
+#[test]
+fn inbound_channel_is_reported_by_technology() {
+    assert_eq!(channel_tech("DAHDI/i1/79235253998-1"), "pstn");
+    assert_eq!(channel_tech("PJSIP/trunk-0000002a"), "sip");
+    assert_eq!(channel_tech("IAX2/peer-4471"), "iax");
+    assert_eq!(channel_tech("Local/501@internal-00000001;2"), "internal");
+    assert_eq!(channel_tech("Console/dsp"), UNKNOWN_TECH);
+    let vars = env("agi_arg_1: 79235253998\nagi_arg_3: inbound\nagi_channel: DAHDI/i1/1-1\n\n").unwrap();
+    assert_eq!(var(&lookup(vars), "INBOUND_TECH"), Some("pstn"));
+}
+
+static TEST_SIP_ROUTES: &[TechRoute] = &[TechRoute { tech: "sip", did: "79235253998", target: "599" }];
+
+#[test]
+fn tech_route_applies_only_to_its_technology() {
+    assert_eq!(tech_route(TEST_SIP_ROUTES, "sip", "79235253998"), Some("599"));
+    assert_eq!(tech_route(TEST_SIP_ROUTES, "pstn", "79235253998"), None);
+    assert_eq!(tech_route(TEST_SIP_ROUTES, "sip", "79235254061"), None);
+}
+
+#[test]
+fn feature_code_runs_its_application_and_hangs_up() {
+    let mut out = Vec::new();
+    run_feature_code(&mut out, "Playback", "tt-monkeys\"").unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "EXEC Playback \"tt-monkeys\\\"\"\nHANGUP\n");
+}
+
+#[test]
+fn sub_extension_is_split_off_the_dial() {
+    assert_eq!(split_extension("79161234567x123"), ("79161234567", Some("123")));
+    assert_eq!(split_extension("8 916 123-45-67 ext. 45"), ("8 916 123-45-67", Some("45")));
+    assert_eq!(split_extension("79161234567, 9"), ("79161234567", Some("9")));
+    assert_eq!(split_extension("79161234567"), ("79161234567", None));
+    assert_eq!(split_extension("x123"), ("x123", None));
+}
+
+#[test]
+fn sub_extension_is_sent_as_dtmf_after_the_pause() {
+    let out = lookup(outbound("79161234567x123", "501"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("79161234567"));
+    assert_eq!(var(&out, "DIAL_POST_DTMF"), Some("ww123"));
+}
+
+static TWO_LEADS: &[(&str, &str)] = &[("2", "73842"), ("9", "73849")];
+
+#[test]
+fn six_digit_prefix_is_chosen_by_lead() {
+    assert_eq!(six_digit_prefix(TWO_LEADS, "212345"), "73842");
+    assert_eq!(six_digit_prefix(TWO_LEADS, "912345"), "73849");
+    assert_eq!(six_digit_prefix(TWO_LEADS, "602313"), SIX_DIGIT_PREFIX);
+    assert_eq!(six_digit_prefix(&[], "212345"), SIX_DIGIT_PREFIX);
+}
+
+#[test]
+fn every_lead_prefix_is_a_local_block() {
+    assert!(in_local_block(TWO_LEADS, "73842212345"));
+    assert!(in_local_block(TWO_LEADS, "73849912345"));
+    assert!(in_local_block(TWO_LEADS, "73843602313"));
+    assert!(!in_local_block(TWO_LEADS, "73845212345"));
+    assert!(!in_local_block(&[], "73842212345"));
+}
+
+static CLOCK_READS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+struct CountingClock;
+
+impl Clock for CountingClock {
+    fn now(&self) -> i64 {
+        CLOCK_READS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        MONDAY_NOON
+    }
+}
+
+#[test]
+fn one_router_serves_several_calls() {
+    let router = Router::with_clock(CountingClock);
+    let first = lookup_at(outbound("73843601000", "501"), &router);
+    assert_eq!(var(&lookup_at(inbound("79235253998", "79161234567"), &router), "DIAL_TARGET"), Some("501"));
+    assert_eq!(lookup_at(outbound("73843601000", "501"), &router), first);
+    // Each outbound call reads the router's clock afresh.
+    assert_eq!(CLOCK_READS.load(std::sync::atomic::Ordering::Relaxed), 2);
+}
+
+#[test]
+fn tee_copies_output_to_the_capture() {
+    let mut tee = Tee { call_id: "test".into(), primary: Vec::new(), secondary: Some(Vec::new()) };
+    set_var(&mut tee, "DIAL_TARGET", "501").unwrap();
+    assert_eq!(tee.secondary.as_deref(), Some(&tee.primary[..]));
+}
+
+#[test]
+fn failing_capture_is_dropped_without_touching_the_output() {
+    let mut tee = Tee { call_id: "test".into(), primary: Vec::new(), secondary: Some(BrokenWriter(io::ErrorKind::Other)) };
+    set_var(&mut tee, "DIAL_TARGET", "501").unwrap();
+    tee.flush().unwrap();
+    assert!(tee.secondary.is_none());
+    assert_eq!(var(&String::from_utf8(tee.primary).unwrap(), "DIAL_TARGET"), Some("501"));
+}
+
+#[test]
+fn quoted_value_is_unwrapped_and_trimmed() {
+    assert_eq!(env_value(" \" 501 \"", ValueWhitespace::Trim), "501");
+    assert_eq!(env_value("  501  ", ValueWhitespace::Trim), "501");
+    assert_eq!(env_value(" \"\"", ValueWhitespace::Trim), "");
+}
+
+#[test]
+fn quoted_value_keeps_its_inner_whitespace_when_preserved() {
+    assert_eq!(env_value(" \" 501 \"", ValueWhitespace::Preserve), " 501 ");
+    assert_eq!(env_value("  501 ", ValueWhitespace::Preserve), " 501 ");
+    assert_eq!(env_value(" \"unterminated", ValueWhitespace::Preserve), "\"unterminated");
+}
+
+#[test]
+fn blocked_prefix_is_lifted_by_a_longer_exception() {
+    let (blocked, exceptions) = (&["7809", "882"][..], &["78095"][..]);
+    assert!(region_blocked("78091234567", blocked, exceptions));
+    assert!(!region_blocked("78095234567", blocked, exceptions));
+    assert!(region_blocked("88212345678", blocked, exceptions));
+    assert!(!region_blocked("79161234567", blocked, exceptions));
+    assert!(region_blocked("78095234567", &["78095"], &["7809"]));
+    assert!(!region_blocked("78091234567", BLOCKED_PREFIXES, BLOCK_EXCEPTIONS));
+}
+
+#[test]
+fn goto_steering_sends_the_channel_to_the_outcome_context() {
+    let goto = |succ, internal, target, reason| steer_command(Steering::Goto, succ, internal, target, reason);
+    assert_eq!(goto(true, true, "501", "").as_deref(), Some("EXEC Goto \"router-internal,501,1\""));
+    assert_eq!(goto(true, false, "79161234567", "").as_deref(), Some("EXEC Goto \"router-external,79161234567,1\""));
+    assert_eq!(goto(false, false, "", "wrong_length").as_deref(), Some("EXEC Goto \"router-failed,wrong_length,1\""));
+    assert_eq!(steer_command(Steering::Gosub, true, true, "501", "").as_deref(), Some("EXEC Gosub \"router-internal,501,1\""));
+    assert_eq!(steer_command(Steering::Variables, true, true, "501", ""), None);
+}
+
+#[test]
+fn formatted_number_keys_are_rejected() {
+    assert!(check_number_keys(&number_keys()).is_ok());
+    assert!(check_number_keys(&[("NUMBER_TO_EXT", "79235253998")]).is_ok());
+    for key in ["7 923 525 3998", "79,235,253,998", "+79235253998", "8(923)5253998", "1234567890123456"] {
+        let err = check_number_keys(&[("DID_RECORDING", key)]).unwrap_err();
+        assert!(err.starts_with("DID_RECORDING number"), "{}", err);
+    }
+}
+
+static ALTERNATES: phf::Map<&'static str, Trunk> = phf::phf_map! { "501" => Trunk::bare("73843600001") };
+const SPARE: Trunk = Trunk::bare("73843600002");
+
+#[test]
+fn fallback_uses_the_callers_alternate_then_the_default() {
+    assert_eq!(fallback_trunk(&ALTERNATES, Some(&SPARE), "501").map(|t| t.name), Some("73843600001"));
+    assert_eq!(fallback_trunk(&ALTERNATES, Some(&SPARE), "502").map(|t| t.name), Some("73843600002"));
+    assert!(fallback_trunk(&ALTERNATES, None, "502").is_none());
+}
+
+#[test]
+fn fallback_without_an_alternate_trunk_fails() {
+    let vars = AgiVars::new("73843601000", "501", Mode::Fallback).with_call_id("test");
+    assert_eq!(status(vars), "no_fallback");
+    assert_eq!(Mode::from_str("fallback"), Some(Mode::Fallback));
+}
+
+#[test]
+fn booleans_render_in_each_style() {
+    assert_eq!((BoolStyle::TrueFalse.render(true), BoolStyle::TrueFalse.render(false)), ("TRUE", "FALSE"));
+    assert_eq!((BoolStyle::OneZero.render(true), BoolStyle::OneZero.render(false)), ("1", "0"));
+    assert_eq!((BoolStyle::YesNo.render(true), BoolStyle::YesNo.render(false)), ("yes", "no"));
+    assert_eq!(var(&lookup(outbound("73843601000", "501")), "LOOKUP_SUCCESS"), Some(flag(true)));
+}
+
+#[test]
+fn blocked_lookup_succeeds_with_blocked_and_its_reason() {
+    let mut out = Vec::new();
+    set_lookup(LookupStatus::Blocked(FailureReason::NotDialable), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(var(&out, "LOOKUP_SUCCESS"), Some("TRUE"));
+    assert_eq!(var(&out, "BLOCKED"), Some("TRUE"));
+    assert_eq!(var(&out, "LOOKUP_REASON"), Some("not_dialable"));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some(""));
+}
+
+#[test]
+fn every_lookup_reports_whether_it_was_blocked() {
+    assert_eq!(var(&lookup(outbound("73843601000", "501")), "BLOCKED"), Some("FALSE"));
+    assert_eq!(var(&lookup(outbound("1", "501")), "BLOCKED"), Some("FALSE"));
+}
+
+/// A directory on a local port that answers one request with `response`,
+/// or holds the connection open without answering when it is `None`.
+fn directory(response: Option<&'static str>) -> Directory {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = Box::leak(listener.local_addr().unwrap().to_string().into_boxed_str());
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = io::BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while request.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+            line.clear();
+        }
+        match response {
+            Some(r) => { let _ = stream.write_all(r.as_bytes()); }
+            None => std::thread::sleep(Duration::from_secs(2)),
+        }
+    });
+    Directory { addr, host: "directory", path: "/cli/{ext}", timeout_ms: 200 }
+}
+
+#[test]
+fn directory_returns_the_presentation_number() {
+    let d = directory(Some("HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n\"+73843600100\"\n"));
+    assert_eq!(fetch_cli(&d, "501").unwrap().as_deref(), Some("+73843600100"));
+}
+
+#[test]
+fn directory_without_an_entry_returns_none() {
+    let d = directory(Some("HTTP/1.0 404 Not Found\r\n\r\n"));
+    assert_eq!(fetch_cli(&d, "501").unwrap(), None);
+}
+
+#[test]
+fn directory_answering_garbage_is_an_error() {
+    let d = directory(Some("HTTP/1.0 200 OK\r\n\r\nnot a number"));
+    assert!(fetch_cli(&d, "501").is_err());
+}
+
+#[test]
+fn directory_that_never_answers_times_out() {
+    let started = std::time::Instant::now();
+    assert!(fetch_cli(&directory(None), "501").is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn very_long_input_is_rejected_without_sanitizing() {
+    let padded = format!("{}79161234567", " ".repeat(MAX_NUMBER_INPUT));
+    assert_eq!(just_sanitize(&padded), None);
+    assert_eq!(just_sanitize(&"7".repeat(100_000)), None);
+    assert_eq!(status(outbound(&format!("{}7", "-".repeat(1 << 20)), "501")), "normalize_failed_wrong_length");
+    let at_cap = format!("{}79161234567", " ".repeat(MAX_NUMBER_INPUT - 11));
+    assert_eq!(just_sanitize(&at_cap).as_deref(), Some("79161234567"));
+}
+
+#[test]
+fn inbound_call_from_our_number_names_its_extension_when_enabled() {
+    let mut out = Vec::new();
+    handle_inbound(inbound("79235253998", "8 (384) 360-17-73"), true, &mut out).unwrap();
+    assert_eq!(var(&String::from_utf8(out).unwrap(), "CALLER_EXTENSION"), Some("502"));
+    let mut out = Vec::new();
+    handle_inbound(inbound("79235253998", "73831234567"), true, &mut out).unwrap();
+    assert_eq!(var(&String::from_utf8(out).unwrap(), "CALLER_EXTENSION"), None);
+    assert_eq!(var(&lookup(inbound("79235253998", "8 (384) 360-17-73")), "CALLER_EXTENSION"), None);
+}
+
+#[test]
+fn batch_takes_an_optional_instant() {
+    let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(batch_at(&args(&[])), Some(None));
+    assert_eq!(batch_at(&args(&["--at", "1704085200"])), Some(Some(MONDAY_NOON)));
+    assert_eq!(batch_at(&args(&["--at", "noon"])), None);
+    assert_eq!(batch_at(&args(&["--at"])), None);
+}
+
+static CLIR: phf::Set<&'static str> = phf::phf_set! { "502" };
+
+#[test]
+fn only_clir_extensions_withhold_their_caller_id() {
+    assert!(withholds_callerid(&CLIR, Some("502")));
+    assert!(!withholds_callerid(&CLIR, Some("501")));
+    assert!(!withholds_callerid(&CLIR, None));
+    let out = lookup(outbound("73843601000", "502"));
+    assert_eq!(var(&out, "OUTBOUND_CLIR"), None);
+    assert_eq!(var(&out, "DIAL_CALLERID"), Some("79235254061"));
+}
+
+#[test]
+fn statuses_summarize_as_kind_and_value() {
+    assert_eq!(LookupStatus::Internal("501").summary(), ("internal", Cow::Borrowed("501")));
+    let external = LookupStatus::External { target: "73843601000".into(), report_internal: false };
+    assert_eq!(external.summary(), ("external", Cow::Borrowed("73843601000")));
+    assert_eq!(LookupStatus::Failure(FailureReason::NotPermitted).summary(), ("failure", Cow::Borrowed(FailureReason::NotPermitted.as_str())));
+    assert_eq!(LookupStatus::Blocked(FailureReason::NotDialable).summary().0, "blocked");
+}
+
+#[test]
+fn device_mode_names_known_extensions_only() {
+    let out = lookup(AgiVars::new("501", "", Mode::Device).with_call_id("test"));
+    assert_eq!(var(&out, "DEVICE_STATE_TARGET"), Some("PJSIP/501"));
+    assert_eq!(var(&out, "LOOKUP_SUCCESS"), Some(flag(true)));
+    let out = lookup(AgiVars::new("599", "", Mode::Device).with_call_id("test"));
+    assert_eq!(var(&out, "DEVICE_STATE_TARGET"), None);
+    assert_eq!(var(&out, "LOOKUP_REASON"), Some(FailureReason::UnknownExtension.as_str()));
+}
+
+#[test]
+fn dials_without_digits_fail_as_empty() {
+    for dialed in ["", "   ", "abc", "*#", "sip:alice@pbx"] {
+        let out = lookup(outbound(dialed, "501"));
+        assert_eq!(var(&out, "LOOKUP_REASON"), Some(FailureReason::EmptyDial.as_str()), "{:?}", dialed);
+    }
+}
+
+#[test]
+fn home_country_numbers_of_the_wrong_length_are_malformed() {
+    let malformed = FailureReason::MalformedNationalNumber.as_str();
+    assert_eq!(status(outbound("7916123456", "501")), malformed);
+    assert_eq!(status(outbound("791612345678", "501")), malformed);
+    assert_eq!(status(outbound("12345", "501")), FailureReason::WrongLength.as_str());
+    assert_eq!(length_failure("7 (916) 123-45"), FailureReason::MalformedNationalNumber);
+}
+
+#[test]
+fn json_dump_sorts_map_keys_and_escapes_strings() {
+    let mut out = String::new();
+    Json::map([("b", Json::str("say \"hi\"\n")), ("a", Json::Arr(vec![]))]).write(&mut out, 0);
+    assert_eq!(out, "{\n  \"a\": [],\n  \"b\": \"say \\\"hi\\\"\\u000a\"\n}");
+    let mut dump = String::new();
+    dump_routes().write(&mut dump, 0);
+    assert!(dump.contains("\"outbound\": ["));
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> &'a Json {
+    let Json::Obj(entries) = json else { panic!("{} is not in an object", key) };
+    &entries.iter().find(|(k, _)| k == key).unwrap_or_else(|| panic!("no {}", key)).1
+}
+
+fn text(json: &Json) -> &str {
+    let Json::Str(s) = json else { panic!("not a string") };
+    s
+}
+
+#[test]
+fn dumped_trunk_is_the_expected_json() {
+    let mut out = String::new();
+    Json::trunk(&SPARE).write(&mut out, 0);
+    assert_eq!(out, r#"{
+  "name": "73843600002",
+  "tech": "PJSIP",
+  "target_template": "{target}@{trunk}",
+  "options": "Ttr",
+  "night_callerid": null,
+  "outbound_prefix": "",
+  "callerid_format": "stored"
+}"#);
+}
+
+#[test]
+fn dumped_routes_match_the_live_router() {
+    let dump = dump_routes();
+    let Json::Obj(routes) = field(&dump, "number_to_ext") else { panic!("number_to_ext is not an object") };
+    assert!(!routes.is_empty());
+    for (number, ext) in routes {
+        assert_eq!(status(outbound(number, "")), text(ext), "{}", number);
+    }
+    let Json::Obj(trunks) = field(&dump, "ext_to_trunk") else { panic!("ext_to_trunk is not an object") };
+    for (ext, trunk) in trunks {
+        let out = lookup(outbound("73843601000", ext));
+        assert_eq!(var(&out, "DIAL_TRUNK"), Some(text(field(trunk, "name"))), "{}", ext);
+    }
+    let Json::Arr(emergency) = field(&dump, "emergency_numbers") else { panic!("emergency_numbers is not a list") };
+    for number in emergency {
+        assert_eq!(status(outbound(text(number), "501")), text(number));
+    }
+}
+
+#[test]
+fn trunk_technology_is_emitted_only_when_set() {
+    let mut out = Vec::new();
+    set_trunk(&mut out, &SPARE).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(var(&out, "DIAL_TRUNK_TECH"), Some("PJSIP"));
+    assert_eq!(var(&out, "DIAL_TRUNK_NUMBER"), Some("73843600002"));
+    let mut out = Vec::new();
+    set_trunk(&mut out, &Trunk { tech: "", ..SPARE }).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(var(&out, "DIAL_TRUNK_TECH"), None);
+    assert_eq!(var(&out, "DIAL_TRUNK"), Some("73843600002"));
+}
+
+static DESK_THEN_MOBILE: phf::Map<&'static str, &'static [FollowStep]> = phf::phf_map! {
+    "501" => &[FollowStep { target: "502", timeout_secs: 15 }, FollowStep { target: "89161234567", timeout_secs: 25 }],
+};
+
+fn follow_me(ext: &str, step: &str) -> (LookupStatus<'static>, String) {
+    let mut vars = AgiVars::new(ext, "", Mode::FollowMe).with_call_id("test");
+    vars.step = step.to_owned();
+    let mut out = Vec::new();
+    let status = handle_follow_me(&vars, &DESK_THEN_MOBILE, MONDAY_NOON, true, &mut out).unwrap();
+    (status, String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn follow_me_walks_the_steps_then_hands_back_the_extension() {
+    let (status, out) = follow_me("501", "");
+    assert!(matches!(status, LookupStatus::Internal("502")));
+    assert_eq!(var(&out, "FOLLOW_ME_TIMEOUT"), Some("15"));
+    assert_eq!(var(&out, "FOLLOW_ME_NEXT"), Some("1"));
+    let (status, out) = follow_me("501", "1");
+    assert!(matches!(status, LookupStatus::External { ref target, .. } if target.ends_with("79161234567")));
+    assert_eq!(var(&out, "FOLLOW_ME_DONE"), Some(flag(false)));
+    assert_eq!(var(&out, "DIAL_TRUNK"), Some("79235253998"));
+    assert_eq!(var(&out, "DIAL_CALLERID"), Some("79235253998"));
+    assert_eq!(var(&out, "DIAL_STRING"), Some("PJSIP/79161234567@79235253998"));
+    let (status, out) = follow_me("501", "2");
+    assert!(matches!(status, LookupStatus::Internal("501")));
+    assert_eq!(var(&out, "FOLLOW_ME_DONE"), Some(flag(true)));
+    assert!(matches!(follow_me("501", "next").0, LookupStatus::Failure(FailureReason::BadStep)));
+    assert!(matches!(follow_me("502", "").0, LookupStatus::Failure(FailureReason::UnknownExtension)));
+}
+
+#[test]
+fn extension_without_a_trunk_fails_external_dials_under_reject() {
+    assert_eq!(missing_trunk(CallerPolicy::Ignore, "test", "599"), None);
+    assert_eq!(missing_trunk(CallerPolicy::Warn, "test", "599"), None);
+    assert_eq!(missing_trunk(CallerPolicy::Reject, "test", "599"), Some(FailureReason::NoTrunkForExtension));
+    assert_eq!(status(outbound("73843601000", "599")), FailureReason::NoTrunkForExtension.as_str());
+    assert_eq!(status(outbound("73843602313", "599")), "501");
+}
+
+#[test]
+fn feature_codes_keep_only_the_configured_symbols() {
+    assert_eq!(feature_code(" *21*502# ", ""), "*21*502#");
+    assert_eq!(feature_code("*21 * 502 #", ""), "*21 * 502 #");
+    assert_eq!(feature_code("*21 * 502 #", "*#"), "*21*502#");
+    assert_eq!(feature_code("*21-502#", "*"), "*21502");
+    assert_eq!(feature_code("", "*#"), "");
+}
+
+static LANGUAGES: phf::Map<&'static str, &'static str> = phf::phf_map! { "79235255049" => "en" };
+
+#[test]
+fn mapped_dids_get_their_own_language_and_the_rest_the_default() {
+    assert_eq!(call_language(&LANGUAGES, "79235255049"), "en");
+    assert_eq!(call_language(&LANGUAGES, "79235253998"), DEFAULT_LANGUAGE);
+    assert_eq!(var(&lookup(inbound("79235253998", "73831234567")), "CALL_LANGUAGE"), Some(DEFAULT_LANGUAGE));
+}
+
+#[test]
+fn only_formatted_dials_get_an_audit_entry() {
+    assert_eq!(audit_entry("test", "79235253998", "79235253998", None), None);
+    assert_eq!(
+        audit_entry("test", "8 (923) 525-39-98", "79235253998", None).as_deref(),
+        Some("test\t8 (923) 525-39-98\t79235253998\n"),
+    );
+    assert_eq!(
+        audit_entry("test", "8 (923) 525-39-98", "79235253998", Some(4)).as_deref(),
+        Some("test\t* (***) ***-39-98\t*******3998\n"),
+    );
+}
+
+static NOTES: phf::Map<&'static str, &'static [(&'static str, &'static str)]> = phf::phf_map! {
+    "79235254061" => &[("dept", "Sales \"East\""), ("note", "reception, ask for Olga")],
+};
+
+#[test]
+fn route_metadata_emits_only_whitelisted_fields_escaped() {
+    let mut out = Vec::new();
+    set_route_metadata(&mut out, &NOTES, "79235254061").unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out, "SET VARIABLE ROUTE_DEPT \"Sales \\\"East\\\"\"\n");
+    let mut out = Vec::new();
+    set_route_metadata(&mut out, &NOTES, "79235253998").unwrap();
+    assert!(out.is_empty());
+}
+
+const POLICIES: [ForeignElevenPolicy; 2] = [ForeignElevenPolicy::Reject, ForeignElevenPolicy::Country("375")];
+
+#[test]
+fn in_country_eleven_digits_are_never_foreign() {
+    for policy in POLICIES {
+        assert_eq!(foreign_eleven("79235253998", &["1"], policy), Ok(None));
+        assert_eq!(foreign_eleven("8 923 525-39-98", &["1"], policy), Ok(None));
+    }
+    assert_eq!(status(outbound("89235253998", "502")), "501");
+}
+
+#[test]
+fn known_foreign_eleven_digits_go_out_as_they_are() {
+    for policy in POLICIES {
+        assert_eq!(foreign_eleven("1 999 555-12-34", &["1"], policy), Ok(Some("19995551234".to_owned())));
+    }
+}
+
+#[test]
+fn unknown_country_eleven_digits_follow_the_policy() {
+    assert_eq!(foreign_eleven("29123456789", &["1"], ForeignElevenPolicy::Reject), Err(FailureReason::UnknownCountry));
+    assert_eq!(
+        foreign_eleven("29123456789", &["1"], ForeignElevenPolicy::Country("375")),
+        Ok(Some("37529123456789".to_owned())),
+    );
+    assert_eq!(status(outbound("29123456789", "501")), FailureReason::UnknownCountry.as_str());
+}
+
+const FALLBACK_SOURCES: Sources = Sources {
+    dialed: &["agi_arg_1", "agi_extension"],
+    caller: &["agi_arg_2", "agi_callerid"],
+    mode: &["agi_arg_3", "agi_arg_5"],
+};
+
+#[test]
+fn dialed_comes_from_the_next_source_when_the_first_is_empty() {
+    let lines = "agi_extension: 89235253998\nagi_callerid: 502\nagi_arg_1: \nagi_arg_2: \"\"\n\n";
+    let vars = AgiVars::from_reader(io::Cursor::new(lines), &FALLBACK_SOURCES).unwrap();
+    assert_eq!((vars.dialed.as_str(), vars.caller.as_str()), ("89235253998", "502"));
+}
+
+#[test]
+fn first_source_with_a_value_wins() {
+    let lines = "agi_extension: 89235253998\nagi_arg_1: 73843601000\n\n";
+    let vars = AgiVars::from_reader(io::Cursor::new(lines), &FALLBACK_SOURCES).unwrap();
+    assert_eq!(vars.dialed, "73843601000");
+}
+
+#[test]
+fn mode_from_a_later_source_is_trimmed() {
+    let lines = "agi_arg_3:   \nagi_arg_5: \"  inbound \"\n\n";
+    let vars = AgiVars::from_reader(io::Cursor::new(lines), &FALLBACK_SOURCES).unwrap();
+    assert_eq!(vars.mode, Some(Mode::Inbound));
+}
+
+#[test]
+fn shipped_sources_ignore_agi_extension() {
+    assert_eq!(env("agi_extension: 89235253998\n\n").unwrap().dialed, "");
+}
+
+#[test]
+fn emergency_number_goes_out_over_the_callers_trunk() {
+    let out = lookup(outbound("112", "501"));
+    assert_eq!(var(&out, "LOOKUP_SUCCESS"), Some(flag(true)));
+    assert_eq!(var(&out, "IS_INTERNAL_DEST"), Some(flag(false)));
+    assert_eq!(var(&out, "DIAL_TARGET"), Some("112"));
+    assert_eq!(var(&out, "DIAL_STRING"), Some("PJSIP/112@79235253998"));
+}
+
+#[test]
+fn short_code_104_still_rings_its_extension() {
+    assert!(!is_emergency("104"));
+    assert_eq!(status(outbound("104", "502")), "501");
+}
+
+#[test]
+fn malformed_caller_can_still_dial_an_emergency_number() {
+    let dial = |dialed: &str| {
+        let mut out = Vec::new();
+        handle_outbound(outbound(dialed, "42"), MONDAY_NOON, false, CallerPolicy::Reject, false, false, &mut out).unwrap()
+    };
+    assert!(matches!(dial("73843601000"), LookupStatus::Failure(FailureReason::MalformedCaller)));
+    assert!(matches!(dial("112"), LookupStatus::External { ref target, .. } if target == "112"));
+}
+
+#[test]
+fn caller_without_a_trunk_can_still_dial_an_emergency_number() {
+    assert_eq!(status(outbound("73843601000", "599")), FailureReason::NoTrunkForExtension.as_str());
+    assert_eq!(status(outbound("112", "599")), "112");
+    let fallback = |dialed: &str| AgiVars::new(dialed, "501", Mode::Fallback).with_call_id("test");
+    assert_eq!(status(fallback("73843601000")), FailureReason::NoFallback.as_str());
+    assert_eq!(status(fallback("112")), "112");
+}
+
+#[test]
+fn check_trunk_rejects_a_trunk_without_target() {
+    let t = Trunk { target_template: "{trunk}", ..Trunk::bare("79230000900") };
+    assert!(check_trunk(&t, "PREMIUM_TRUNK", None).unwrap_err().contains("PREMIUM_TRUNK"));
+    assert_eq!(check_trunk(&Trunk::bare("79230000900"), "PREMIUM_TRUNK", None), Ok(()));
+}
+
+#[test]
+fn extension_dialing_a_number_that_routes_to_itself_is_a_self_call() {
+    let self_call = FailureReason::SelfCall.as_str();
+    assert_eq!(status(outbound("79235253998", "501")), self_call);
+    assert_eq!(status(outbound("602313", "501")), self_call);
+    assert_eq!(status(outbound("135", "502")), self_call);
+    assert_eq!(status(outbound("79235253998", "502")), "501");
+}
+
+#[test]
+fn failed_command_is_named_with_its_call() {
+    let mut sent = Vec::new();
+    let mut channel = AgiChannel::new("test".into(), io::Cursor::new("510 Invalid or unknown command\n"), &mut sent);
+    channel.write_all(b"SET VARIABLE ").unwrap();
+    channel.write_all(b"A 1\n").unwrap();
+    assert!(channel.command.is_empty());
+    assert_eq!(
+        failed_command_warning("test", b"SET VARIABLE A 1", 510),
+        "fastagi_router[test]: AGI command \"SET VARIABLE A 1\" failed with 510",
+    );
+}
+
+fn classify_args(args: &[&str]) -> (String, ExitCode) {
+    let args: Vec<String> = args.iter().map(|&a| a.to_owned()).collect();
+    let mut out = Vec::new();
+    let code = classify(&at(MONDAY_NOON), &args, &mut out);
+    (String::from_utf8(out).unwrap(), code)
+}
+
+#[test]
+fn classify_exits_by_outcome() {
+    assert_eq!(classify_args(&["79235253998", "--caller", "502"]), ("79235253998\tinternal\t501\n".to_owned(), ExitCode::from(0)));
+    assert_eq!(
+        classify_args(&["73843601000", "--caller", "501", "--mode", "outbound"]),
+        ("73843601000\texternal\t73843601000\n".to_owned(), ExitCode::from(1)),
+    );
+    assert_eq!(classify_args(&["79235253998", "--caller", "501"]), ("79235253998\tfailure\tself_call\n".to_owned(), ExitCode::from(2)));
+    assert_eq!(classify_args(&["501", "--mode", "outbont"]), ("501\tfailure\tbad_mode\n".to_owned(), ExitCode::from(2)));
+}
+
+#[test]
+fn classify_without_a_number_is_a_usage_error() {
+    assert_eq!(classify_args(&[]), (String::new(), Exit::ParseError.into()));
+    assert_eq!(classify_args(&["501", "--caller"]), (String::new(), Exit::ParseError.into()));
+}